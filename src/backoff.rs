@@ -0,0 +1,94 @@
+use rand::{self, Rng};
+use std::cmp;
+use std::time::Duration;
+
+/**
+ * Exponential backoff policy used between reconnection attempts.
+ *
+ * Every call to `next_delay` returns the current delay, randomly moved by up to `jitter` (a
+ * fraction of the delay), and multiplies the following one by `multiplier` up to `max_delay`.
+ */
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial_delay: Duration,
+    multiplier: f64,
+    max_delay: Duration,
+    jitter: f64,
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, multiplier: f64, max_delay: Duration, jitter: f64) -> Self {
+        Backoff {
+            initial_delay: initial_delay,
+            multiplier: multiplier.max(1.0),
+            max_delay: cmp::max(initial_delay, max_delay),
+            jitter: jitter.max(0.0).min(1.0),
+            current: None,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = match self.current {
+            None => self.initial_delay,
+            Some(current) => cmp::min(self.max_delay, from_millis(millis(current) * self.multiplier)),
+        };
+        self.current = Some(delay);
+
+        if self.jitter == 0.0 {
+            return delay;
+        }
+
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter, 1.0 + self.jitter);
+        from_millis(millis(delay) * factor)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+fn from_millis(millis: f64) -> Duration {
+    Duration::from_millis(millis.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(backoff: &mut Backoff, count: usize) -> Vec<u64> {
+        (0..count).map(|_| backoff.next_delay()).map(|delay| millis(delay) as u64).collect()
+    }
+
+    #[test]
+    fn the_delay_is_multiplied_after_every_attempt() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(60), 0.0);
+        assert_eq!(delays(&mut backoff, 5), [100, 200, 400, 800, 1600]);
+    }
+
+    #[test]
+    fn the_delay_stops_growing_at_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), 3.0, Duration::from_millis(1000), 0.0);
+        assert_eq!(delays(&mut backoff, 5), [100, 300, 900, 1000, 1000]);
+    }
+
+    #[test]
+    fn a_reset_starts_again_from_the_initial_delay() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(60), 0.0);
+        delays(&mut backoff, 3);
+        backoff.reset();
+        assert_eq!(delays(&mut backoff, 2), [100, 200]);
+    }
+
+    #[test]
+    fn the_jitter_stays_within_its_fraction() {
+        let mut backoff = Backoff::new(Duration::from_millis(1000), 1.0, Duration::from_millis(1000), 0.2);
+        for delay in delays(&mut backoff, 100) {
+            assert!(delay >= 800 && delay <= 1200, "{}", delay);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use event::Event;
+use futures::{Async, AsyncSink, Poll, StartSend, Sink};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use stubborn_sink::StubbornSink;
+
+/**
+ * virtual nodes of each member on the consistent hashing ring
+ */
+const RING_REPLICAS: usize = 100;
+
+#[derive(Clone, Debug)]
+pub enum Strategy {
+    RoundRobin,
+    LeastOutstanding,
+    /**
+     * the key is the first capture group of the regex (or the whole match, without groups),
+     * lines without a match are hashed as a whole
+     */
+    ConsistentHash(Regex),
+}
+
+impl Strategy {
+    pub fn parse(name: &str, key: Option<String>) -> Result<Self, String> {
+        match name {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-outstanding" => Ok(Strategy::LeastOutstanding),
+            "hash" => {
+                let key = key.ok_or("the hash balance strategy requires --balance-key".to_string())?;
+                Regex::new(&key).map(Strategy::ConsistentHash).map_err(|err| format!("invalid balance key: {}", err))
+            }
+            other => Err(format!("unknown balance strategy `{}`", other)),
+        }
+    }
+}
+
+/**
+ * Spreads lines over a pool of StubbornSink, one for each destination.
+ *
+ * Lines go to the connected members, the others are used only when none is connected. When a
+ * member loses its connection, cannot connect in time or stops acknowledging lines, the lines it
+ * has not delivered yet are taken back and sent to the other members.
+ */
+pub struct Balancer {
+    members: Vec<StubbornSink>,
+    strategy: Strategy,
+    next: usize,
+    ring: Vec<(u64, usize)>,
+    rerouting: VecDeque<Event>,
+}
+
+impl Balancer {
+    pub fn new(members: Vec<StubbornSink>, strategy: Strategy) -> Self {
+        let mut ring = Vec::with_capacity(members.len() * RING_REPLICAS);
+        for (index, member) in members.iter().enumerate() {
+            for replica in 0..RING_REPLICAS {
+                ring.push((hash(&format!("{}#{}", member.name(), replica)), index));
+            }
+        }
+        ring.sort();
+
+        Balancer {
+            members: members,
+            strategy: strategy,
+            next: 0,
+            ring: ring,
+            rerouting: VecDeque::new(),
+        }
+    }
+
+    fn pick(&mut self, line: &str) -> Option<usize> {
+        /**
+         * a member still connecting would hold the line back, it is picked only when no member is
+         * connected
+         */
+        let any_connected = self.members.iter().any(|member| member.is_connected());
+        let available = |members: &Vec<StubbornSink>, index: usize| if any_connected {
+            members[index].is_connected()
+        } else {
+            !members[index].is_down()
+        };
+
+        match self.strategy {
+            Strategy::RoundRobin => {
+                let count = self.members.len();
+                let picked = (0..count).map(|offset| (self.next + offset) % count)
+                    .find(|index| available(&self.members, *index));
+                if let Some(index) = picked {
+                    self.next = index + 1;
+                }
+                picked
+            }
+            Strategy::LeastOutstanding => {
+                (0..self.members.len())
+                    .filter(|index| available(&self.members, *index))
+                    .min_by_key(|index| self.members[*index].outstanding())
+            }
+            Strategy::ConsistentHash(ref key) => {
+                let key = match key.captures(line) {
+                    Some(captures) => captures.get(1).or(captures.get(0)).map(|m| m.as_str()).unwrap_or(line),
+                    None => line,
+                };
+                let point = hash(&key);
+                let start = match self.ring.binary_search(&(point, 0)) {
+                    Ok(position) | Err(position) => position,
+                };
+
+                (0..self.ring.len())
+                    .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+                    .find(|index| available(&self.members, *index))
+            }
+        }
+    }
+
+    fn dispatch(&mut self, event: Event) -> StartSend<Event, io::Error> {
+        match self.pick(&event.line) {
+            Some(index) => self.members[index].start_send(event),
+            None => Ok(AsyncSink::NotReady(event)),
+        }
+    }
+
+    /**
+     * Drives every member and takes back what the members that dropped out of the pool have not
+     * delivered, then sends it again to the healthy ones
+     */
+    fn reroute(&mut self) -> Poll<(), io::Error> {
+        let mut complete = true;
+        for member in self.members.iter_mut() {
+            /**
+             * members are kept connected even when idle, so that the ones that dropped out come
+             * back into the pool
+             */
+            member.poll_connection()?;
+            if let Async::NotReady = member.poll_complete()? {
+                complete = false;
+            }
+            if member.is_down() && member.outstanding() > 0 {
+                let undelivered = member.take_undelivered();
+                warn!("Destination {} dropped out of the pool, rerouting {} lines", member.name(), undelivered.len());
+                self.rerouting.extend(undelivered);
+            }
+        }
+
+        while let Some(event) = self.rerouting.pop_front() {
+            if let AsyncSink::NotReady(event) = self.dispatch(event)? {
+                self.rerouting.push_front(event);
+                return Ok(Async::NotReady);
+            }
+        }
+
+        if complete {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Sink for Balancer {
+    type SinkItem = Event;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, event: Event) -> StartSend<Event, io::Error> {
+        self.reroute()?;
+        if !self.rerouting.is_empty() {
+            return Ok(AsyncSink::NotReady(event));
+        }
+
+        self.dispatch(event)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.reroute());
+        if !self.rerouting.is_empty() {
+            return Ok(Async::NotReady);
+        }
+
+        /**
+         * rerouted lines have been sent after the members were polled
+         */
+        for member in self.members.iter_mut() {
+            try_ready!(member.poll_complete());
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::{Core, Handle};
+
+    fn balancer(addresses: &[&str], args: &[&str], strategy: Strategy, handle: &Handle) -> Balancer {
+        let mut args = args.to_vec();
+        args.extend_from_slice(&["-d", addresses[0]]);
+        let configuration = ::test_conf(&args);
+        let members = addresses.iter()
+            .map(|address| StubbornSink::new(vec![address.to_string()], configuration.clone(), handle.clone()))
+            .collect();
+        Balancer::new(members, strategy)
+    }
+
+    fn by_key() -> Strategy {
+        Strategy::ConsistentHash(Regex::new("user=(\\w+)").unwrap())
+    }
+
+    #[test]
+    fn round_robin_takes_the_members_in_turn() {
+        let core = Core::new().unwrap();
+        let mut balancer = balancer(&["a:1", "b:1", "c:1"], &[], Strategy::RoundRobin, &core.handle());
+        let picked = (0..4).map(|_| balancer.pick("line").unwrap()).collect::<Vec<_>>();
+        assert_eq!(picked, [0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn the_ring_holds_every_member_as_many_times() {
+        let core = Core::new().unwrap();
+        let balancer = balancer(&["a:1", "b:1", "c:1"], &[], by_key(), &core.handle());
+        assert_eq!(balancer.ring.len(), 3 * RING_REPLICAS);
+        assert!(balancer.ring.windows(2).all(|points| points[0] <= points[1]));
+        for member in 0..3 {
+            assert_eq!(balancer.ring.iter().filter(|&&(_, index)| index == member).count(), RING_REPLICAS);
+        }
+    }
+
+    #[test]
+    fn lines_with_the_same_key_go_to_the_same_member() {
+        let core = Core::new().unwrap();
+        let mut balancer = balancer(&["a:1", "b:1", "c:1"], &[], by_key(), &core.handle());
+        for user in 0..20 {
+            let member = balancer.pick(&format!("user={} logged in", user));
+            assert_eq!(balancer.pick(&format!("user={} logged out", user)), member);
+        }
+
+        let members = (0..20).filter_map(|user| balancer.pick(&format!("user={}", user))).collect::<Vec<_>>();
+        assert!(members.iter().any(|member| *member != members[0]));
+    }
+
+    #[test]
+    fn only_the_keys_of_a_removed_member_move() {
+        let core = Core::new().unwrap();
+        let mut three = balancer(&["a:1", "b:1", "c:1"], &[], by_key(), &core.handle());
+        let mut two = balancer(&["a:1", "b:1"], &[], by_key(), &core.handle());
+        for user in 0..100 {
+            let line = format!("user={}", user);
+            let member = three.pick(&line).unwrap();
+            if member < 2 {
+                assert_eq!(two.pick(&line), Some(member), "{}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn lines_left_unacknowledged_are_rerouted() {
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let acking = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addresses = [silent.local_addr().unwrap().to_string(), acking.local_addr().unwrap().to_string()];
+
+        /**
+         * the silent member reads lines and never acknowledges them
+         */
+        let silent = thread::spawn(move || {
+            let (socket, _) = silent.accept().unwrap();
+            BufReader::new(socket).lines().filter_map(|line| line.ok()).count()
+        });
+        let acking = thread::spawn(move || {
+            let (mut socket, _) = acking.accept().unwrap();
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            let mut lines = vec![];
+            while lines.len() < 4 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.trim_end().splitn(2, ' ');
+                let seq = parts.next().unwrap().to_string();
+                lines.push(parts.next().unwrap().to_string());
+                socket.write_all(format!("ACK {}\n", seq).as_bytes()).unwrap();
+            }
+            lines.sort();
+            lines
+        });
+
+        let mut core = Core::new().unwrap();
+        let addresses = [&addresses[0][..], &addresses[1][..]];
+        let args = ["--ack", "--ack-timeout", "200", "--reconnect-delay", "10000"];
+        let balancer = balancer(&addresses, &args, Strategy::RoundRobin, &core.handle());
+        let events = stream::iter_ok::<_, io::Error>((1..5).map(|line| Event::new(format!("line {}", line))));
+        core.run(balancer.send_all(events)).unwrap();
+        drop(core);
+
+        assert_eq!(acking.join().unwrap(), ["line 1", "line 2", "line 3", "line 4"]);
+        assert!(silent.join().unwrap() > 0);
+    }
+}
@@ -1,6 +1,8 @@
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_line;
+extern crate openssl;
+extern crate tokio_openssl;
 
 use futures::future::Future;
 use futures::sync::mpsc::UnboundedSender;
@@ -8,14 +10,29 @@ use futures::{Stream, Sink};
 use futures::IntoFuture;
 use std::io::{self, ErrorKind};
 use tokio_core::io::Io;
-use tokio_core::net::TcpListener;
+use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor::Handle;
 use tokio_line::LineCodec;
 use tokio_core::reactor::Core;
 use std::io::Write;
 use std::fs::OpenOptions;
+use std::env;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use tokio_openssl::{SslAcceptorExt, SslStream};
 
 fn main () {
+    let args: Vec<String> = env::args().collect();
+    let ack = args.iter().any(|arg| arg == "--ack");
+    let arg_value = |name: &str| args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1)).cloned();
+
+    /**
+     * with `--tls-cert` and `--tls-key` (self-signed is fine) connections are accepted over TLS
+     */
+    let acceptor = match (arg_value("--tls-cert"), arg_value("--tls-key")) {
+        (Some(cert), Some(key)) => Some(tls_acceptor(&cert, &key)),
+        _ => None,
+    };
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
@@ -24,21 +41,79 @@ fn main () {
     let connections = listener.incoming();
 
     let server = connections.for_each(move |(socket, _)| {
-        let transport = socket.framed(LineCodec);
-        let process_connection = transport.for_each(move |line| {
-            println!("{}", line);
-
-            let mut file = OpenOptions::new().append(true).open("/tmp/fake-server.txt").unwrap();
-            file.write_all((line.to_string() + "\n").as_bytes());
-
-            Ok(())
-        })
-        .map_err(|_| ());
-
-        handle.spawn(process_connection);
+        match acceptor {
+            None => serve(socket, ack, &handle),
+            Some(ref acceptor) => {
+                let inner_handle = handle.clone();
+                let handshake = acceptor.accept_async(socket)
+                    .map(move |socket| serve(TlsStream(socket), ack, &inner_handle))
+                    .map_err(|_| ());
+                handle.spawn(handshake);
+            }
+        }
 
         Ok(())
     });
 
     core.run(server).unwrap();
 }
+
+fn tls_acceptor(cert: &str, key: &str) -> SslAcceptor {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    {
+        let context = &mut *builder;
+        context.set_certificate_chain_file(cert).unwrap();
+        context.set_private_key_file(key, SslFiletype::PEM).unwrap();
+    }
+    builder.build()
+}
+
+/**
+ * tokio-openssl streams implement the tokio-io traits only, `framed` needs `Io`
+ */
+struct TlsStream(SslStream<TcpStream>);
+
+impl io::Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.0, buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Io for TlsStream {}
+
+fn serve<S: Io + 'static>(socket: S, ack: bool, handle: &Handle) {
+    let (sender, receiver) = socket.framed(LineCodec).split();
+    let process_connection = receiver.filter_map(move |line| {
+        /**
+         * in ack mode every line is prefixed by its id, which is sent back once the line is stored
+         */
+        let (id, line) = if ack {
+            let mut parts = line.splitn(2, ' ');
+            (parts.next().map(|id| id.to_string()), parts.next().unwrap_or("").to_string())
+        } else {
+            (None, line)
+        };
+
+        println!("{}", line);
+
+        let mut file = OpenOptions::new().append(true).open("/tmp/fake-server.txt").unwrap();
+        file.write_all((line.to_string() + "\n").as_bytes());
+
+        id.map(|id| format!("ACK {}", id))
+    })
+    .forward(sender)
+    .map(|_| ())
+    .map_err(|_| ());
+
+    handle.spawn(process_connection);
+}
@@ -0,0 +1,440 @@
+use event::Event;
+use filter::Filter;
+use futures::future::Future;
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Poll, StartSend, Stream, Sink};
+use spool::{Spool, SpoolReader, SpoolWriter};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Interval};
+
+/**
+ * What happens to an event received while the buffer is full
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /**
+     * clients are not read anymore until there is room again
+     */
+    Block,
+    DropNewest,
+    DropOldest,
+    /**
+     * lines are appended to the spool and read back once the buffer has been drained
+     */
+    Spill,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, String> {
+        match policy {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "spill" => Ok(OverflowPolicy::Spill),
+            other => Err(format!("unknown overflow policy `{}`", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Capacity {
+    pub lines: Option<usize>,
+    pub bytes: Option<usize>,
+}
+
+struct Inner {
+    queue: VecDeque<Event>,
+    bytes: usize,
+    capacity: Capacity,
+    policy: OverflowPolicy,
+    overflow: Option<(SpoolWriter, SpoolReader)>,
+    /**
+     * events it does not accept are dropped before taking any room
+     */
+    filter: Option<Filter>,
+    spilled: usize,
+    dropped: u64,
+    overflowing: bool,
+    senders: usize,
+    blocked_senders: Vec<Task>,
+    blocked_receiver: Option<Task>,
+}
+
+/**
+ * Every connected client receives a clone of the sender, data is read from the receiver in the
+ * same order it has been sent.
+ */
+pub struct BufferSender {
+    inner: Rc<RefCell<Inner>>,
+}
+
+pub struct BufferReceiver {
+    inner: Rc<RefCell<Inner>>,
+}
+
+pub fn bounded(capacity: Capacity, policy: OverflowPolicy, overflow: Option<Spool>) -> (BufferSender, BufferReceiver) {
+    /**
+     * what the previous run spilled is still to be delivered, before anything new
+     */
+    let spilled = overflow.as_ref().map(|spool| spool.undelivered()).unwrap_or(0);
+    let inner = Rc::new(RefCell::new(Inner {
+        queue: VecDeque::new(),
+        bytes: 0,
+        capacity: capacity,
+        policy: policy,
+        overflow: overflow.map(|spool| spool.split()),
+        filter: None,
+        spilled: spilled,
+        dropped: 0,
+        overflowing: false,
+        senders: 1,
+        blocked_senders: vec![],
+        blocked_receiver: None,
+    }));
+
+    (BufferSender { inner: inner.clone() }, BufferReceiver { inner: inner })
+}
+
+impl Inner {
+    fn is_full(&self, incoming: &Event) -> bool {
+        let lines_exceeded = self.capacity.lines.map(|lines| self.queue.len() >= lines).unwrap_or(false);
+        let bytes_exceeded = self.capacity.bytes
+            .map(|bytes| !self.queue.is_empty() && self.bytes + incoming.line.len() > bytes)
+            .unwrap_or(false);
+
+        lines_exceeded || bytes_exceeded
+    }
+
+    fn push(&mut self, event: Event) {
+        self.bytes += event.line.len();
+        self.queue.push_back(event);
+        self.wake_receiver();
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        let event = self.queue.pop_front();
+        if let Some(ref event) = event {
+            self.bytes -= event.line.len();
+        }
+        event
+    }
+
+    fn count_drop(&mut self) {
+        self.dropped += 1;
+        if !self.overflowing {
+            self.overflowing = true;
+            warn!("Buffer is full, {} lines dropped so far", self.dropped);
+        }
+    }
+
+    fn spill(&mut self, event: Event) -> io::Result<()> {
+        match self.overflow {
+            Some((ref mut writer, _)) => {
+                writer.start_send(event)?;
+            }
+            None => return Err(io::Error::new(io::ErrorKind::Other, "spill policy requires a spool")),
+        }
+        if self.spilled == 0 {
+            warn!("Buffer is full, spilling lines to the spool");
+        }
+        self.spilled += 1;
+        self.wake_receiver();
+
+        Ok(())
+    }
+
+    fn unspill(&mut self) -> io::Result<Option<Event>> {
+        if self.spilled == 0 {
+            return Ok(None);
+        }
+
+        let event = match self.overflow {
+            Some((_, ref mut reader)) => {
+                match reader.poll()? {
+                    Async::Ready(event) => {
+                        reader.commit()?;
+                        event
+                    }
+                    Async::NotReady => None,
+                }
+            }
+            None => None,
+        };
+        match event {
+            Some(_) => self.spilled -= 1,
+            /**
+             * the spool is read to its end, some records of the previous run being unreadable
+             */
+            None => self.spilled = 0,
+        }
+
+        Ok(event)
+    }
+
+    fn wake_receiver(&mut self) {
+        if let Some(task) = self.blocked_receiver.take() {
+            task.notify();
+        }
+    }
+
+    fn wake_senders(&mut self) {
+        for task in self.blocked_senders.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+impl BufferSender {
+    /**
+     * Filters the events sent through this sender and all of its clones
+     */
+    pub fn filter_with(self, filter: Filter) -> Self {
+        self.inner.borrow_mut().filter = Some(filter);
+        self
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.inner.borrow().dropped
+    }
+
+    /**
+     * Logs, every `interval`, how many lines the overflow policy dropped since the last report,
+     * until the buffer is gone
+     */
+    pub fn report_drops(&self,
+                        name: String,
+                        interval: Duration,
+                        handle: &Handle)
+                        -> io::Result<Box<Future<Item = (), Error = io::Error>>> {
+        let inner = Rc::downgrade(&self.inner);
+        let mut reported = 0;
+        let reporting = Interval::new(interval, handle)?
+            .map(move |_| inner.upgrade())
+            .take_while(|inner| Ok(inner.is_some()))
+            .for_each(move |inner| {
+                let dropped = inner.expect("a live buffer").borrow().dropped;
+                if dropped > reported {
+                    warn!("The buffer of {} dropped {} lines since the last report, {} in total",
+                          name,
+                          dropped - reported,
+                          dropped);
+                    reported = dropped;
+                }
+                Ok(())
+            });
+
+        Ok(Box::new(reporting))
+    }
+
+    /**
+     * Whether every one of `events` fits in the buffer right now; with the spill policy there is
+     * always room, in the spool
+     */
+    pub fn has_room_for(&self, events: &[Event]) -> bool {
+        let inner = self.inner.borrow();
+        if inner.policy == OverflowPolicy::Spill {
+            return true;
+        }
+
+        let bytes = events.iter().map(|event| event.line.len()).sum::<usize>();
+        let lines_fit = inner.capacity.lines.map(|lines| inner.queue.len() + events.len() <= lines).unwrap_or(true);
+        let bytes_fit = inner.capacity.bytes.map(|limit| inner.bytes + bytes <= limit).unwrap_or(true);
+
+        lines_fit && bytes_fit
+    }
+}
+
+impl Clone for BufferSender {
+    fn clone(&self) -> Self {
+        self.inner.borrow_mut().senders += 1;
+        BufferSender { inner: self.inner.clone() }
+    }
+}
+
+impl Drop for BufferSender {
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            inner.wake_receiver();
+        }
+    }
+}
+
+impl Sink for BufferSender {
+    type SinkItem = Event;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, event: Event) -> StartSend<Event, io::Error> {
+        let mut inner = self.inner.borrow_mut();
+
+        if !inner.filter.as_ref().map(|filter| filter.accepts(&event)).unwrap_or(true) {
+            return Ok(AsyncSink::Ready);
+        }
+
+        if inner.policy == OverflowPolicy::Spill && inner.spilled > 0 {
+            /**
+             * once something has been spilled, everything goes to the spool to keep the ordering
+             */
+            inner.spill(event)?;
+            return Ok(AsyncSink::Ready);
+        }
+
+        if !inner.is_full(&event) {
+            inner.overflowing = false;
+            inner.push(event);
+            return Ok(AsyncSink::Ready);
+        }
+
+        let policy = inner.policy;
+        match policy {
+            OverflowPolicy::Block => {
+                inner.blocked_senders.push(task::current());
+                return Ok(AsyncSink::NotReady(event));
+            }
+            OverflowPolicy::DropNewest => inner.count_drop(),
+            OverflowPolicy::DropOldest => {
+                while inner.is_full(&event) && inner.pop().is_some() {
+                    inner.count_drop();
+                }
+                inner.push(event);
+            }
+            OverflowPolicy::Spill => inner.spill(event)?,
+        }
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Stream for BufferReceiver {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, io::Error> {
+        let mut inner = self.inner.borrow_mut();
+
+        let event = match inner.pop() {
+            Some(event) => Some(event),
+            None => inner.unspill()?,
+        };
+
+        match event {
+            Some(event) => {
+                inner.wake_senders();
+                Ok(Async::Ready(Some(event)))
+            }
+            None if inner.senders == 0 && inner.spilled == 0 => Ok(Async::Ready(None)),
+            None => {
+                inner.blocked_receiver = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    fn buffer(lines: Option<usize>, bytes: Option<usize>, policy: OverflowPolicy) -> (BufferSender, BufferReceiver) {
+        let capacity = Capacity {
+            lines: lines,
+            bytes: bytes,
+        };
+        bounded(capacity, policy, None)
+    }
+
+    /**
+     * Sends the lines within a task, as a full buffer with the `block` policy parks the sender
+     */
+    fn send(buftx: &mut BufferSender, lines: &[&str]) -> Vec<bool> {
+        future::lazy(|| {
+                let sent = lines.iter()
+                    .map(|line| buftx.start_send(Event::new(line.to_string())).unwrap().is_ready())
+                    .collect::<Vec<_>>();
+                Ok::<_, ()>(sent)
+            })
+            .wait()
+            .unwrap()
+    }
+
+    fn received(buftx: BufferSender, bufrx: BufferReceiver) -> Vec<String> {
+        drop(buftx);
+        bufrx.map(|event| event.line).collect().wait().unwrap()
+    }
+
+    #[test]
+    fn senders_are_held_back_at_the_line_limit() {
+        let (mut buftx, mut bufrx) = buffer(Some(2), None, OverflowPolicy::Block);
+        assert_eq!(send(&mut buftx, &["one", "two", "three"]), [true, true, false]);
+        assert!(!buftx.has_room_for(&[Event::new("three".to_string())]));
+
+        future::lazy(|| bufrx.poll()).wait().unwrap();
+        assert!(buftx.has_room_for(&[Event::new("three".to_string())]));
+        assert_eq!(send(&mut buftx, &["three"]), [true]);
+        assert_eq!(received(buftx, bufrx), ["two", "three"]);
+    }
+
+    #[test]
+    fn senders_are_held_back_at_the_byte_limit() {
+        let (mut buftx, bufrx) = buffer(None, Some(10), OverflowPolicy::Block);
+        assert_eq!(send(&mut buftx, &["12345", "67890", "x"]), [true, true, false]);
+        assert_eq!(received(buftx, bufrx), ["12345", "67890"]);
+    }
+
+    #[test]
+    fn a_line_larger_than_the_byte_limit_fits_in_an_empty_buffer() {
+        let (mut buftx, bufrx) = buffer(None, Some(4), OverflowPolicy::Block);
+        assert_eq!(send(&mut buftx, &["larger", "next"]), [true, false]);
+        assert_eq!(received(buftx, bufrx), ["larger"]);
+    }
+
+    #[test]
+    fn the_newest_lines_are_dropped_at_the_line_limit() {
+        let (mut buftx, bufrx) = buffer(Some(2), None, OverflowPolicy::DropNewest);
+        assert_eq!(send(&mut buftx, &["one", "two", "three", "four"]), [true, true, true, true]);
+        assert_eq!(buftx.dropped(), 2);
+        assert_eq!(received(buftx, bufrx), ["one", "two"]);
+    }
+
+    #[test]
+    fn the_oldest_lines_are_dropped_at_the_line_limit() {
+        let (mut buftx, bufrx) = buffer(Some(2), None, OverflowPolicy::DropOldest);
+        assert_eq!(send(&mut buftx, &["one", "two", "three", "four"]), [true, true, true, true]);
+        assert_eq!(buftx.dropped(), 2);
+        assert_eq!(received(buftx, bufrx), ["three", "four"]);
+    }
+
+    #[test]
+    fn as_many_old_lines_as_needed_are_dropped_at_the_byte_limit() {
+        let (mut buftx, bufrx) = buffer(None, Some(10), OverflowPolicy::DropOldest);
+        assert_eq!(send(&mut buftx, &["aaaaa", "bbbbb", "cccccccc"]), [true, true, true]);
+        assert_eq!(buftx.dropped(), 2);
+        assert_eq!(received(buftx, bufrx), ["cccccccc"]);
+    }
+
+    #[test]
+    fn drop_reports_end_with_the_buffer() {
+        let mut core = Core::new().unwrap();
+        let (mut buftx, bufrx) = buffer(Some(1), None, OverflowPolicy::DropNewest);
+        let reporting = buftx.report_drops("test".to_string(), Duration::from_millis(10), &core.handle()).unwrap();
+        send(&mut buftx, &["one", "two"]);
+        drop(buftx);
+        drop(bufrx);
+
+        core.run(reporting).unwrap();
+    }
+}
@@ -0,0 +1,631 @@
+use futures::{Future, Stream};
+use resolve;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::rc::Rc;
+use std::str::{self, FromStr};
+use std::time::Duration;
+use tokio_core::io::{Codec, EasyBuf};
+use tokio_core::reactor::{Handle, Interval};
+
+/**
+ * digits of the length of an octet counted record, more than enough for any length
+ */
+const MAX_OCTET_COUNT_DIGITS: usize = 20;
+
+/**
+ * client addresses whose oversized records are counted one by one between two reports
+ */
+const MAX_OVERSIZE_CLIENTS: usize = 1000;
+const OTHER_CLIENTS: &'static str = "other clients";
+
+/**
+ * How records are delimited on a connection
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    Newline,
+    /**
+     * records end with a NUL byte, as GELF over TCP
+     */
+    Nul,
+    /**
+     * every record is preceded by its length, as a 4 bytes big-endian integer
+     */
+    LengthPrefix,
+    /**
+     * `LENGTH SP RECORD`, as syslog over TCP (RFC 6587)
+     */
+    OctetCounting,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(framing: &str) -> Result<Self, String> {
+        match framing {
+            "newline" => Ok(Framing::Newline),
+            "nul" => Ok(Framing::Nul),
+            "length-prefix" => Ok(Framing::LengthPrefix),
+            "octet-counting" => Ok(Framing::OctetCounting),
+            other => Err(format!("unknown framing `{}`", other)),
+        }
+    }
+}
+
+/**
+ * What happens to a record longer than the maximum size
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OversizePolicy {
+    /**
+     * the record is cut at the maximum size, the rest is thrown away
+     */
+    Truncate,
+    /**
+     * the record is returned in pieces of the maximum size
+     */
+    Split,
+    /**
+     * the client is disconnected
+     */
+    Reject,
+}
+
+impl FromStr for OversizePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, String> {
+        match policy {
+            "truncate" => Ok(OversizePolicy::Truncate),
+            "split" => Ok(OversizePolicy::Split),
+            "reject" => Ok(OversizePolicy::Reject),
+            other => Err(format!("unknown oversize policy `{}`", other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecordLimit {
+    pub max_size: usize,
+    pub policy: OversizePolicy,
+}
+
+/**
+ * Records longer than the limit received from each client IP address since the last report.
+ *
+ * The counts are forgotten once reported, and at most `MAX_OVERSIZE_CLIENTS` addresses are
+ * counted one by one in between, the others together.
+ */
+#[derive(Clone, Default)]
+pub struct OversizeCounts {
+    counts: Rc<RefCell<BTreeMap<String, u64>>>,
+}
+
+impl OversizeCounts {
+    fn count(&self, peer: &str, limit: &RecordLimit) {
+        let mut counts = self.counts.borrow_mut();
+        let client = resolve::host(peer);
+        let key = if counts.len() < MAX_OVERSIZE_CLIENTS || counts.contains_key(client) {
+            client
+        } else {
+            OTHER_CLIENTS
+        };
+        let count = counts.entry(key.to_string()).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            warn!("Client {} sent a record larger than {} bytes ({:?})", peer, limit.max_size, limit.policy);
+        }
+    }
+
+    /**
+     * Logs, every `interval`, how many oversized records each client sent since the last report
+     */
+    pub fn report(&self, interval: Duration, handle: &Handle) -> io::Result<Box<Future<Item = (), Error = io::Error>>> {
+        let counts = self.counts.clone();
+        let reporting = Interval::new(interval, handle)?.for_each(move |_| {
+            let reported = mem::replace(&mut *counts.borrow_mut(), BTreeMap::new());
+            for (client, count) in reported {
+                warn!("Client {} sent {} records larger than the limit", client, count);
+            }
+            Ok(())
+        });
+
+        Ok(Box::new(reporting))
+    }
+}
+
+/**
+ * What is left of an oversized record that is being thrown away
+ */
+enum Skip {
+    Nothing,
+    Bytes(usize),
+    ToDelimiter,
+}
+
+/**
+ * Records framed as `framing`, a record being a `String` as with LineCodec.
+ *
+ * Without a limit a record is buffered until it is complete, whatever its size.
+ */
+pub struct RecordCodec {
+    framing: Framing,
+    limit: Option<RecordLimit>,
+    peer: String,
+    oversize_counts: OversizeCounts,
+    skip: Skip,
+    /**
+     * bytes of a length-prefixed record being split which are still to be returned
+     */
+    remaining: usize,
+    /**
+     * a delimited record is being split, it has already been counted
+     */
+    splitting: bool,
+}
+
+impl RecordCodec {
+    pub fn new(framing: Framing) -> Self {
+        RecordCodec {
+            framing: framing,
+            limit: None,
+            peer: String::new(),
+            oversize_counts: OversizeCounts::default(),
+            skip: Skip::Nothing,
+            remaining: 0,
+            splitting: false,
+        }
+    }
+
+    /**
+     * Applies `limit` to the records received from `peer`
+     */
+    pub fn with_limit(mut self, limit: Option<RecordLimit>, peer: String, oversize_counts: OversizeCounts) -> Self {
+        self.limit = limit;
+        self.peer = peer;
+        self.oversize_counts = oversize_counts;
+        self
+    }
+
+    /**
+     * whether no record is partially read
+     */
+    pub fn is_idle(&self) -> bool {
+        match self.skip {
+            Skip::Nothing => self.remaining == 0 && !self.splitting,
+            _ => false,
+        }
+    }
+
+    fn oversized(&self, limit: &RecordLimit, size: Option<usize>) -> io::Error {
+        self.oversize_counts.count(&self.peer, limit);
+        let size = size.map(|size| format!("of {} bytes", size)).unwrap_or_else(|| "without end".to_string());
+        io::Error::new(ErrorKind::InvalidData,
+                       format!("record {} is larger than {} bytes", size, limit.max_size))
+    }
+
+    /**
+     * Throws away what is left of a truncated record, returns false while there is more to throw
+     */
+    fn skip(&mut self, buf: &mut EasyBuf) -> bool {
+        self.skip = match self.skip {
+            Skip::Nothing => return true,
+            Skip::Bytes(bytes) => {
+                let skipped = cmp::min(bytes, buf.len());
+                buf.drain_to(skipped);
+                if skipped < bytes {
+                    Skip::Bytes(bytes - skipped)
+                } else {
+                    Skip::Nothing
+                }
+            }
+            Skip::ToDelimiter => {
+                let delimiter = self.delimiter();
+                match buf.as_slice().iter().position(|byte| *byte == delimiter) {
+                    Some(end) => {
+                        buf.drain_to(end + 1);
+                        Skip::Nothing
+                    }
+                    None => {
+                        let length = buf.len();
+                        buf.drain_to(length);
+                        Skip::ToDelimiter
+                    }
+                }
+            }
+        };
+
+        match self.skip {
+            Skip::Nothing => true,
+            _ => false,
+        }
+    }
+
+    fn delimiter(&self) -> u8 {
+        match self.framing {
+            Framing::Nul => 0,
+            _ => b'\n',
+        }
+    }
+
+    fn decode_delimited(&mut self, buf: &mut EasyBuf) -> io::Result<Option<EasyBuf>> {
+        let delimiter = self.delimiter();
+        let limit = match self.limit {
+            None => return Ok(decode_delimited(buf, delimiter)),
+            Some(limit) => limit,
+        };
+
+        let end = buf.as_slice().iter().take(limit.max_size + 1).position(|byte| *byte == delimiter);
+        if end.is_some() {
+            self.splitting = false;
+            return Ok(decode_delimited(buf, delimiter));
+        }
+        if buf.len() <= limit.max_size {
+            return Ok(None);
+        }
+
+        match limit.policy {
+            OversizePolicy::Reject => Err(self.oversized(&limit, None)),
+            OversizePolicy::Truncate => {
+                self.oversize_counts.count(&self.peer, &limit);
+                self.skip = Skip::ToDelimiter;
+                Ok(Some(buf.drain_to(limit.max_size)))
+            }
+            OversizePolicy::Split => {
+                if !self.splitting {
+                    self.oversize_counts.count(&self.peer, &limit);
+                    self.splitting = true;
+                }
+                Ok(Some(buf.drain_to(limit.max_size)))
+            }
+        }
+    }
+
+    /**
+     * A record of `length` bytes after a header of `header` bytes
+     */
+    fn decode_counted(&mut self, buf: &mut EasyBuf, header: usize, length: usize) -> io::Result<Option<EasyBuf>> {
+        let limit = match self.limit {
+            Some(limit) if length > limit.max_size => limit,
+            _ => {
+                if buf.len() < header + length {
+                    return Ok(None);
+                }
+                buf.drain_to(header);
+                return Ok(Some(buf.drain_to(length)));
+            }
+        };
+
+        if limit.policy == OversizePolicy::Reject {
+            return Err(self.oversized(&limit, Some(length)));
+        }
+        if buf.len() < header + limit.max_size {
+            return Ok(None);
+        }
+
+        self.oversize_counts.count(&self.peer, &limit);
+        buf.drain_to(header);
+        match limit.policy {
+            OversizePolicy::Truncate => self.skip = Skip::Bytes(length - limit.max_size),
+            _ => self.remaining = length - limit.max_size,
+        }
+        Ok(Some(buf.drain_to(limit.max_size)))
+    }
+}
+
+impl Codec for RecordCodec {
+    type In = String;
+    type Out = String;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<String>> {
+        if !self.skip(buf) {
+            return Ok(None);
+        }
+
+        if self.remaining > 0 {
+            let max_size = self.limit.map(|limit| limit.max_size).unwrap_or(self.remaining);
+            let size = cmp::min(self.remaining, max_size);
+            if buf.len() < size {
+                return Ok(None);
+            }
+            self.remaining -= size;
+            return Ok(Some(to_string(&buf.drain_to(size), self.framing)));
+        }
+
+        let record = match self.framing {
+            Framing::Newline | Framing::Nul => self.decode_delimited(buf)?,
+            Framing::LengthPrefix => {
+                match length_prefix(buf) {
+                    Some(length) => self.decode_counted(buf, 4, length)?,
+                    None => None,
+                }
+            }
+            Framing::OctetCounting => {
+                match octet_count(buf)? {
+                    Some((header, length)) => self.decode_counted(buf, header, length)?,
+                    None => None,
+                }
+            }
+        };
+
+        Ok(record.map(|record| to_string(&record, self.framing)))
+    }
+
+    fn encode(&mut self, record: String, buf: &mut Vec<u8>) -> io::Result<()> {
+        match self.framing {
+            Framing::Newline => {
+                buf.extend_from_slice(record.as_bytes());
+                buf.push(b'\n');
+            }
+            Framing::Nul => {
+                buf.extend_from_slice(record.as_bytes());
+                buf.push(0);
+            }
+            Framing::LengthPrefix => {
+                let length = record.len() as u32;
+                buf.extend_from_slice(&[(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8]);
+                buf.extend_from_slice(record.as_bytes());
+            }
+            Framing::OctetCounting => buf.extend_from_slice(format!("{} {}", record.len(), record).as_bytes()),
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Invalid UTF-8 is replaced rather than refused. Trailing line endings are dropped from newline
+ * delimited records only, the other framings keep the record as it was sent.
+ */
+fn to_string(record: &EasyBuf, framing: Framing) -> String {
+    let record = String::from_utf8_lossy(record.as_slice());
+    match framing {
+        Framing::Newline => record.trim_end_matches(&['\r', '\n'][..]).to_string(),
+        _ => record.into_owned(),
+    }
+}
+
+fn decode_delimited(buf: &mut EasyBuf, delimiter: u8) -> Option<EasyBuf> {
+    let end = match buf.as_slice().iter().position(|byte| *byte == delimiter) {
+        Some(end) => end,
+        None => return None,
+    };
+    let record = buf.drain_to(end);
+    buf.drain_to(1);
+    Some(record)
+}
+
+/**
+ * The length in the 4 bytes big-endian header
+ */
+fn length_prefix(buf: &EasyBuf) -> Option<usize> {
+    if buf.len() < 4 {
+        return None;
+    }
+    Some(buf.as_slice()[..4].iter().fold(0usize, |length, byte| length << 8 | *byte as usize))
+}
+
+/**
+ * The size of the `LENGTH SP` header and the length in it
+ */
+fn octet_count(buf: &EasyBuf) -> io::Result<Option<(usize, usize)>> {
+    let space = match buf.as_slice().iter().take(MAX_OCTET_COUNT_DIGITS + 1).position(|byte| *byte == b' ') {
+        Some(space) => space,
+        None if buf.len() > MAX_OCTET_COUNT_DIGITS => {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid record length"))
+        }
+        None => return Ok(None),
+    };
+    let length = str::from_utf8(&buf.as_slice()[..space])
+        .ok()
+        .and_then(|length| length.parse::<usize>().ok())
+        .ok_or(io::Error::new(ErrorKind::InvalidData, "invalid record length"))?;
+
+    Ok(Some((space + 1, length)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAMINGS: [Framing; 4] = [Framing::Newline, Framing::Nul, Framing::LengthPrefix, Framing::OctetCounting];
+
+    fn encode(framing: Framing, records: &[&str]) -> Vec<u8> {
+        let mut codec = RecordCodec::new(framing);
+        let mut buf = vec![];
+        for record in records {
+            codec.encode(record.to_string(), &mut buf).unwrap();
+        }
+        buf
+    }
+
+    /**
+     * Decodes what is received in `chunks`, as the bytes arrive
+     */
+    fn decode(codec: &mut RecordCodec, chunks: &[&[u8]]) -> io::Result<Vec<String>> {
+        let mut buf = EasyBuf::new();
+        let mut records = vec![];
+        for chunk in chunks {
+            buf.get_mut().extend_from_slice(chunk);
+            while let Some(record) = codec.decode(&mut buf)? {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    #[test]
+    fn records_are_encoded_as_framed() {
+        assert_eq!(encode(Framing::Newline, &["ab", "c"]), b"ab\nc\n".to_vec());
+        assert_eq!(encode(Framing::Nul, &["ab", "c"]), b"ab\0c\0".to_vec());
+        assert_eq!(encode(Framing::LengthPrefix, &["ab", "c"]), b"\0\0\0\x02ab\0\0\0\x01c".to_vec());
+        assert_eq!(encode(Framing::OctetCounting, &["ab", "c"]), b"2 ab1 c".to_vec());
+    }
+
+    #[test]
+    fn encoded_records_are_decoded_back() {
+        let records = ["first", "", "with a space", "héllo"];
+        for framing in &FRAMINGS {
+            let encoded = encode(*framing, &records);
+            let decoded = decode(&mut RecordCodec::new(*framing), &[&encoded]).unwrap();
+            assert_eq!(decoded, records, "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn records_arriving_byte_by_byte_are_decoded() {
+        let records = ["first", "second record"];
+        for framing in &FRAMINGS {
+            let encoded = encode(*framing, &records);
+            let chunks = encoded.chunks(1).collect::<Vec<_>>();
+            let decoded = decode(&mut RecordCodec::new(*framing), &chunks).unwrap();
+            assert_eq!(decoded, records, "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn incomplete_records_are_kept_for_later() {
+        for framing in &FRAMINGS {
+            let encoded = encode(*framing, &["complete", "incomplete"]);
+            let mut codec = RecordCodec::new(*framing);
+            let decoded = decode(&mut codec, &[&encoded[..encoded.len() - 1]]).unwrap();
+            assert_eq!(decoded, ["complete"], "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn line_endings_are_dropped() {
+        let decoded = decode(&mut RecordCodec::new(Framing::Newline), &[b"dos\r\nunix\n"]).unwrap();
+        assert_eq!(decoded, ["dos", "unix"]);
+    }
+
+    #[test]
+    fn line_endings_are_kept_with_the_other_framings() {
+        for framing in &[Framing::Nul, Framing::LengthPrefix, Framing::OctetCounting] {
+            let encoded = encode(*framing, &["line\n", "dos\r\n"]);
+            let decoded = decode(&mut RecordCodec::new(*framing), &[&encoded]).unwrap();
+            assert_eq!(decoded, ["line\n", "dos\r\n"], "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn invalid_octet_counts_are_refused() {
+        assert!(decode(&mut RecordCodec::new(Framing::OctetCounting), &[b"a2 ab"]).is_err());
+        assert!(decode(&mut RecordCodec::new(Framing::OctetCounting), &[b"123456789012345678901234"]).is_err());
+    }
+
+    fn limited(framing: Framing, policy: OversizePolicy, counts: &OversizeCounts) -> RecordCodec {
+        let limit = RecordLimit {
+            max_size: 5,
+            policy: policy,
+        };
+        RecordCodec::new(framing).with_limit(Some(limit), "peer".to_string(), counts.clone())
+    }
+
+    fn oversized(counts: &OversizeCounts) -> u64 {
+        counts.counts.borrow().get("peer").cloned().unwrap_or(0)
+    }
+
+    /**
+     * Decodes `records` with a limit of 5 bytes, received at once and byte by byte
+     */
+    fn decode_limited(framing: Framing, policy: OversizePolicy, records: &[&str]) -> io::Result<(Vec<String>, u64)> {
+        let encoded = encode(framing, records);
+
+        let counts = OversizeCounts::default();
+        let at_once = decode(&mut limited(framing, policy, &counts), &[&encoded]);
+        let at_once_count = oversized(&counts);
+
+        let counts = OversizeCounts::default();
+        let chunks = encoded.chunks(1).collect::<Vec<_>>();
+        let in_pieces = decode(&mut limited(framing, policy, &counts), &chunks);
+        assert_eq!(at_once.as_ref().ok(), in_pieces.as_ref().ok(), "{:?} {:?}", framing, policy);
+        assert_eq!(at_once_count, oversized(&counts), "{:?} {:?}", framing, policy);
+
+        at_once.map(|records| (records, at_once_count))
+    }
+
+    #[test]
+    fn records_at_the_limit_are_untouched() {
+        for framing in &FRAMINGS {
+            for policy in &[OversizePolicy::Truncate, OversizePolicy::Split, OversizePolicy::Reject] {
+                let (records, count) = decode_limited(*framing, *policy, &["12345", "1234", ""]).unwrap();
+                assert_eq!(records, ["12345", "1234", ""], "{:?} {:?}", framing, policy);
+                assert_eq!(count, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn records_over_the_limit_are_truncated() {
+        for framing in &FRAMINGS {
+            let (records, count) = decode_limited(*framing, OversizePolicy::Truncate, &["123456", "12345678901", "ok"])
+                .unwrap();
+            assert_eq!(records, ["12345", "12345", "ok"], "{:?}", framing);
+            assert_eq!(count, 2);
+        }
+    }
+
+    #[test]
+    fn records_over_the_limit_are_split() {
+        for framing in &FRAMINGS {
+            let (records, count) = decode_limited(*framing, OversizePolicy::Split, &["123456", "12345678901", "ok"])
+                .unwrap();
+            assert_eq!(records, ["12345", "6", "12345", "67890", "1", "ok"], "{:?}", framing);
+            assert_eq!(count, 2);
+        }
+    }
+
+    #[test]
+    fn records_over_the_limit_are_rejected() {
+        for framing in &FRAMINGS {
+            assert!(decode_limited(*framing, OversizePolicy::Reject, &["ok", "123456"]).is_err(), "{:?}", framing);
+        }
+    }
+
+    #[test]
+    fn oversized_records_are_counted_by_client_address() {
+        let counts = OversizeCounts::default();
+        let limit = RecordLimit {
+            max_size: 5,
+            policy: OversizePolicy::Truncate,
+        };
+        counts.count("10.0.0.1:40000", &limit);
+        counts.count("10.0.0.1:40001", &limit);
+        counts.count("[::1]:40000", &limit);
+
+        let expected = [("10.0.0.1".to_string(), 2), ("::1".to_string(), 1)];
+        assert_eq!(*counts.counts.borrow(), expected.iter().cloned().collect());
+    }
+
+    #[test]
+    fn clients_past_the_maximum_are_counted_together() {
+        let counts = OversizeCounts::default();
+        let limit = RecordLimit {
+            max_size: 5,
+            policy: OversizePolicy::Truncate,
+        };
+        for client in 0..MAX_OVERSIZE_CLIENTS + 2 {
+            counts.count(&format!("client {}", client), &limit);
+        }
+        counts.count("client 0", &limit);
+
+        let counts = counts.counts.borrow();
+        assert_eq!(counts.len(), MAX_OVERSIZE_CLIENTS + 1);
+        assert_eq!(counts.get("client 0"), Some(&2));
+        assert_eq!(counts.get(OTHER_CLIENTS), Some(&2));
+    }
+
+    #[test]
+    fn a_truncated_record_is_idle_only_once_thrown_away() {
+        let counts = OversizeCounts::default();
+        let mut codec = limited(Framing::Newline, OversizePolicy::Truncate, &counts);
+        assert_eq!(decode(&mut codec, &[b"1234567"]).unwrap(), ["12345"]);
+        assert!(!codec.is_idle());
+        assert_eq!(decode(&mut codec, &[b"89\nok\n"]).unwrap(), ["ok"]);
+        assert!(codec.is_idle());
+    }
+}
@@ -0,0 +1,303 @@
+use event::Event;
+use format::{self, Format};
+use serde_json::{self, Map, Value};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/**
+ * What happens to a line which is not a JSON object
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonJson {
+    /**
+     * the line is delivered unchanged
+     */
+    Pass,
+    /**
+     * the line becomes `{"message":LINE}`, which is then enriched as any other JSON line
+     */
+    Wrap,
+}
+
+impl FromStr for NonJson {
+    type Err = String;
+
+    fn from_str(non_json: &str) -> Result<Self, String> {
+        match non_json {
+            "pass" => Ok(NonJson::Pass),
+            "wrap" => Ok(NonJson::Wrap),
+            other => Err(format!("unknown handling of non JSON lines `{}`", other)),
+        }
+    }
+}
+
+/**
+ * A field of a JSON object, `@fields.level` being the field `level` of the object `@fields`
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path {
+    names: Vec<String>,
+}
+
+impl FromStr for Path {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, String> {
+        let names = path.trim().split('.').map(|name| name.to_string()).collect::<Vec<_>>();
+        if names.iter().any(|name| name.is_empty()) {
+            return Err(format!("invalid field path `{}`", path));
+        }
+        Ok(Path { names: names })
+    }
+}
+
+impl Path {
+    pub fn get<'a>(&self, object: &'a Map<String, Value>) -> Option<&'a Value> {
+        let (last, parents) = self.names.split_last().expect("a path with at least one name");
+        let mut object = object;
+        for name in parents {
+            object = match object.get(name) {
+                Some(&Value::Object(ref child)) => child,
+                _ => return None,
+            };
+        }
+        object.get(last)
+    }
+
+    /**
+     * The metadata field of an event named as the whole path, such as `path` for the tailed files
+     */
+    pub fn get_field<'a>(&self, fields: &'a BTreeMap<String, String>) -> Option<&'a String> {
+        fields.get(&self.names.join("."))
+    }
+
+    /**
+     * Missing parent objects are created, parents which are not objects are replaced
+     */
+    fn set(&self, object: &mut Map<String, Value>, value: Value) {
+        let (last, parents) = self.names.split_last().expect("a path with at least one name");
+        let mut object = object;
+        for name in parents {
+            let child = object.entry(name.clone()).or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            object = match *child {
+                Value::Object(ref mut child) => child,
+                _ => unreachable!(),
+            };
+        }
+        object.insert(last.clone(), value);
+    }
+
+    fn remove(&self, object: &mut Map<String, Value>) -> Option<Value> {
+        let (last, parents) = self.names.split_last().expect("a path with at least one name");
+        let mut object = object;
+        for name in parents {
+            object = match object.get_mut(name) {
+                Some(&mut Value::Object(ref mut child)) => child,
+                _ => return None,
+            };
+        }
+        object.remove(last)
+    }
+}
+
+/**
+ * Changes made to the JSON lines on their way from the buffer to the destinations.
+ *
+ * The changes are applied in this order whatever the order of the options: renames, deletions,
+ * defaults and then added fields.
+ */
+#[derive(Clone, Debug)]
+pub struct Enrichment {
+    /**
+     * fields set to a template (see `Format::Template`) rendered for the event, overwriting them
+     */
+    pub added: Vec<(Path, Format)>,
+    /**
+     * as `added`, only for the fields which are missing
+     */
+    pub defaults: Vec<(Path, Format)>,
+    pub renamed: Vec<(Path, Path)>,
+    pub deleted: Vec<Path>,
+    pub non_json: NonJson,
+}
+
+impl Default for Enrichment {
+    fn default() -> Self {
+        Enrichment {
+            added: vec![],
+            defaults: vec![],
+            renamed: vec![],
+            deleted: vec![],
+            non_json: NonJson::Pass,
+        }
+    }
+}
+
+impl Enrichment {
+    /**
+     * whether events are delivered unchanged
+     */
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.defaults.is_empty() && self.renamed.is_empty() && self.deleted.is_empty() &&
+        self.non_json == NonJson::Pass
+    }
+
+    pub fn apply(&self, mut event: Event) -> Event {
+        if self.is_empty() {
+            return event;
+        }
+
+        let mut object = match serde_json::from_str(&event.line) {
+            Ok(Value::Object(object)) => object,
+            _ => {
+                match self.non_json {
+                    NonJson::Pass => return event,
+                    NonJson::Wrap => {
+                        let mut object = Map::new();
+                        object.insert("message".to_string(), Value::String(event.line.clone()));
+                        object
+                    }
+                }
+            }
+        };
+
+        for &(ref from, ref to) in &self.renamed {
+            if let Some(value) = from.remove(&mut object) {
+                to.set(&mut object, value);
+            }
+        }
+        for path in &self.deleted {
+            path.remove(&mut object);
+        }
+        for &(ref path, ref template) in &self.defaults {
+            if path.get(&object).is_none() {
+                path.set(&mut object, Value::String(template.render(&event)));
+            }
+        }
+        for &(ref path, ref template) in &self.added {
+            path.set(&mut object, Value::String(template.render(&event)));
+        }
+
+        event.line = Value::Object(object).to_string();
+        event
+    }
+}
+
+/**
+ * `PATH=TEMPLATE`, as given to `--add-field` and `--default-field`
+ */
+pub fn parse_field(field: &str) -> Result<(Path, Format), String> {
+    let position = field.find('=').ok_or_else(|| format!("`{}` is not PATH=VALUE", field))?;
+    let path = field[..position].parse()?;
+    let template = format::parse_template(&field[position + 1..])?;
+    Ok((path, Format::Template(template)))
+}
+
+/**
+ * `FROM=TO`, as given to `--rename-field`
+ */
+pub fn parse_rename(rename: &str) -> Result<(Path, Path), String> {
+    let position = rename.find('=').ok_or_else(|| format!("`{}` is not FROM=TO", rename))?;
+    Ok((rename[..position].parse()?, rename[position + 1..].parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> Path {
+        path.parse().unwrap()
+    }
+
+    fn object(json: &str) -> Map<String, Value> {
+        match serde_json::from_str(json).unwrap() {
+            Value::Object(object) => object,
+            _ => panic!("not an object: {}", json),
+        }
+    }
+
+    fn enrich(enrichment: &Enrichment, line: &str) -> String {
+        enrichment.apply(Event::new(line.to_string()).received_on("tcp", None)).line
+    }
+
+    fn json(line: &str) -> Value {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn paths_are_names_separated_by_dots() {
+        assert_eq!(path(" @fields.level ").names, ["@fields", "level"]);
+        assert!("".parse::<Path>().is_err());
+        assert!("a..b".parse::<Path>().is_err());
+        assert!("a.".parse::<Path>().is_err());
+    }
+
+    #[test]
+    fn nested_fields_are_found() {
+        let object = object(r#"{"a":{"b":{"c":1}},"d":2}"#);
+        assert_eq!(path("a.b.c").get(&object), Some(&Value::from(1)));
+        assert_eq!(path("d").get(&object), Some(&Value::from(2)));
+        assert_eq!(path("d.e").get(&object), None);
+        assert_eq!(path("a.x").get(&object), None);
+    }
+
+    #[test]
+    fn missing_parents_are_created_and_others_replaced() {
+        let mut object = object(r#"{"a":1}"#);
+        path("b.c").set(&mut object, Value::from("x"));
+        path("a.d").set(&mut object, Value::from("y"));
+        assert_eq!(Value::Object(object), json(r#"{"a":{"d":"y"},"b":{"c":"x"}}"#));
+    }
+
+    #[test]
+    fn changes_are_applied_in_order() {
+        let enrichment = Enrichment {
+            added: vec![parse_field("source={listener}").unwrap(), parse_field("@meta.host=web1").unwrap()],
+            defaults: vec![parse_field("level=info").unwrap(), parse_field("msg=none").unwrap()],
+            renamed: vec![parse_rename("message=msg").unwrap(), parse_rename("missing=other").unwrap()],
+            deleted: vec![path("secret"), path("nested.token"), path("msg.deeper")],
+            non_json: NonJson::Pass,
+        };
+
+        let line = r#"{"message":"hello","secret":"s","source":"old","nested":{"token":"t","kept":1}}"#;
+        let expected = r#"{"msg":"hello","level":"info","source":"tcp","nested":{"kept":1},"@meta":{"host":"web1"}}"#;
+        assert_eq!(json(&enrich(&enrichment, line)), json(expected));
+    }
+
+    #[test]
+    fn defaults_keep_the_fields_already_set() {
+        let enrichment = Enrichment {
+            defaults: vec![parse_field("level=info").unwrap()],
+            ..Enrichment::default()
+        };
+        assert_eq!(json(&enrich(&enrichment, r#"{"level":"error"}"#)), json(r#"{"level":"error"}"#));
+        assert_eq!(json(&enrich(&enrichment, r#"{"level":null}"#)), json(r#"{"level":null}"#));
+    }
+
+    #[test]
+    fn non_json_lines_are_passed_or_wrapped() {
+        let mut enrichment = Enrichment {
+            added: vec![parse_field("source={listener}").unwrap()],
+            ..Enrichment::default()
+        };
+        assert_eq!(enrich(&enrichment, "plain text"), "plain text");
+        assert_eq!(enrich(&enrichment, "[1,2]"), "[1,2]");
+
+        enrichment.non_json = NonJson::Wrap;
+        assert_eq!(json(&enrich(&enrichment, "plain text")), json(r#"{"message":"plain text","source":"tcp"}"#));
+        assert_eq!(enrich(&Enrichment::default(), " unchanged {"), " unchanged {");
+    }
+
+    #[test]
+    fn invalid_options_are_refused() {
+        assert!(parse_field("level").is_err());
+        assert!(parse_field("=info").is_err());
+        assert!(parse_field("level={unclosed").is_err());
+        assert!(parse_rename("from").is_err());
+        assert!(parse_rename("from=").is_err());
+        assert!("other".parse::<NonJson>().is_err());
+    }
+}
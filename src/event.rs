@@ -0,0 +1,301 @@
+use futures::sync::oneshot;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Once};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static NEXT_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+static SEED_SEQUENCE: Once = Once::new();
+
+/**
+ * The counter starts from the clock, in microseconds, so that the events of a run come after
+ * those of the previous runs still in the spool
+ */
+fn next_sequence() -> u64 {
+    SEED_SEQUENCE.call_once(|| NEXT_SEQUENCE.store(micros(SystemTime::now()) as usize, Ordering::SeqCst));
+    NEXT_SEQUENCE.fetch_add(1, Ordering::SeqCst) as u64
+}
+
+/**
+ * Microseconds since the Unix epoch
+ */
+fn micros(time: SystemTime) -> u64 {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1000) as u64
+}
+
+/**
+ * A line received from a client, together with what is known about where it comes from
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub line: String,
+    /**
+     * `tcp`, `tls`, `unix`, `udp`, `http`, `tail`, `stdin`, `pipe` or `exec`
+     */
+    pub listener: Option<String>,
+    /**
+     * address of the client, for the network listeners
+     */
+    pub peer: Option<String>,
+    pub received_at: SystemTime,
+    /**
+     * increasing in the order the events are received, across runs of the process as long as
+     * the clock does not go back
+     */
+    pub sequence: u64,
+    pub fields: BTreeMap<String, String>,
+    /**
+     * set when the sender waits for the event to be taken care of, it is not spooled
+     */
+    pub receipt: Option<Receipt>,
+}
+
+impl Event {
+    pub fn new(line: String) -> Self {
+        Event {
+            line: line,
+            listener: None,
+            peer: None,
+            received_at: SystemTime::now(),
+            sequence: next_sequence(),
+            fields: BTreeMap::new(),
+            receipt: None,
+        }
+    }
+
+    pub fn received_on(mut self, listener: &str, peer: Option<String>) -> Self {
+        self.listener = Some(listener.to_string());
+        self.peer = peer;
+        self
+    }
+
+    pub fn with_receipt(mut self, receipt: Receipt) -> Self {
+        self.receipt = Some(receipt);
+        self
+    }
+
+    pub fn with_field<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.fields.insert(key.into(), value.into());
+        self
+    }
+
+    /**
+     * Microseconds since the Unix epoch
+     */
+    pub fn received_at_micros(&self) -> u64 {
+        micros(self.received_at)
+    }
+
+    /**
+     * Single line representation used by the spool:
+     * `sequence;received_at;listener;peer<TAB>key=value;key=value<TAB>line`, where `%`, `;`, `=`,
+     * tabs and newlines are percent-encoded and `received_at` is in microseconds
+     */
+    pub fn encode(&self) -> String {
+        let metadata = [self.sequence.to_string(),
+                        self.received_at_micros().to_string(),
+                        self.listener.as_ref().map(|listener| escape(listener)).unwrap_or_default(),
+                        self.peer.as_ref().map(|peer| escape(peer)).unwrap_or_default()]
+            .join(";");
+
+        let fields = self.fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", escape(key), escape(value)))
+            .collect::<Vec<_>>()
+            .join(";");
+
+        format!("{}\t{}\t{}", metadata, fields, escape(&self.line))
+    }
+
+    /**
+     * The reverse of `encode`
+     */
+    pub fn decode(record: &str) -> Result<Self, String> {
+        let sections = record.splitn(3, '\t').collect::<Vec<_>>();
+        if sections.len() != 3 {
+            return Err(format!("spool record without metadata: {}", record));
+        }
+        let metadata = sections[0].split(';').collect::<Vec<_>>();
+        if metadata.len() != 4 {
+            return Err(format!("invalid spool record metadata: {}", sections[0]));
+        }
+        let sequence = metadata[0].parse().map_err(|_| format!("invalid sequence in spool record: {}", metadata[0]))?;
+        let micros = metadata[1]
+            .parse::<u64>()
+            .map_err(|_| format!("invalid receive time in spool record: {}", metadata[1]))?;
+
+        let mut fields = BTreeMap::new();
+        for field in sections[1].split(';').filter(|field| !field.is_empty()) {
+            let mut parts = field.splitn(2, '=');
+            let key = unescape(parts.next().unwrap_or(""));
+            let value = unescape(parts.next().unwrap_or(""));
+            fields.insert(key, value);
+        }
+
+        /**
+         * built as it was received, without taking a new sequence
+         */
+        Ok(Event {
+            line: unescape(sections[2]),
+            listener: unescape_non_empty(metadata[2]),
+            peer: unescape_non_empty(metadata[3]),
+            received_at: UNIX_EPOCH + Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000),
+            sequence: sequence,
+            fields: fields,
+            receipt: None,
+        })
+    }
+}
+
+impl From<String> for Event {
+    fn from(line: String) -> Self {
+        Event::new(line)
+    }
+}
+
+/**
+ * Shared by the events of a batch whose sender waits for them to be taken care of.
+ *
+ * It completes once every copy of these events is gone: `true` when they were all written to the
+ * spool, sent to their destination or discarded on purpose by a filter or an overflow policy, and
+ * `false` when one of them could not be written to the spool.
+ */
+#[derive(Clone)]
+pub struct Receipt {
+    inner: Arc<ReceiptInner>,
+}
+
+struct ReceiptInner {
+    sender: Option<oneshot::Sender<bool>>,
+    failed: AtomicBool,
+}
+
+impl Receipt {
+    pub fn new() -> (Self, oneshot::Receiver<bool>) {
+        let (sender, receiver) = oneshot::channel();
+        let receipt = Receipt {
+            inner: Arc::new(ReceiptInner {
+                sender: Some(sender),
+                failed: AtomicBool::new(false),
+            }),
+        };
+        (receipt, receiver)
+    }
+
+    pub fn fail(&self) {
+        self.inner.failed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ReceiptInner {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(!self.failed.load(Ordering::SeqCst));
+        }
+    }
+}
+
+impl fmt::Debug for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Receipt")
+    }
+}
+
+impl PartialEq for Receipt {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '%' | ';' | '=' | '\t' | '\n' | '\r' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_non_empty(text: &str) -> Option<String> {
+    if text.is_empty() {
+        None
+    } else {
+        Some(unescape(text))
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            unescaped.push(c);
+            continue;
+        }
+
+        let code: String = chars.by_ref().take(2).collect();
+        match u8::from_str_radix(&code, 16) {
+            Ok(byte) => unescaped.push(byte as char),
+            Err(_) => {
+                unescaped.push('%');
+                unescaped.push_str(&code);
+            }
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * the spool keeps the receive time in microseconds
+     */
+    fn received_at_a_microsecond(mut event: Event) -> Event {
+        event.received_at = UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_000);
+        event
+    }
+
+    #[test]
+    fn encoded_events_are_decoded_back() {
+        let event = Event::new("100% of a;b=c\twith tabs\nand newlines\r, héllo".to_string())
+            .received_on("tc;p=", Some("[::1]:514\t%".to_string()))
+            .with_field("key;=%\t", "value\n;=%")
+            .with_field("empty", "");
+        let event = received_at_a_microsecond(event);
+
+        let encoded = event.encode();
+        assert!(!encoded.contains('\n'));
+        assert_eq!(encoded.matches('\t').count(), 2);
+
+        let decoded = Event::decode(&encoded).unwrap();
+        assert_eq!(decoded, event);
+    }
+
+    #[test]
+    fn events_without_metadata_are_decoded_back() {
+        let event = received_at_a_microsecond(Event::new(String::new()));
+        assert_eq!(Event::decode(&event.encode()).unwrap(), event);
+    }
+
+    #[test]
+    fn sequences_start_from_the_clock_and_increase() {
+        let first = Event::new(String::new());
+        let second = Event::new(String::new());
+        assert!(first.sequence > micros(UNIX_EPOCH + Duration::from_secs(1_500_000_000)));
+        assert!(second.sequence > first.sequence);
+    }
+
+    #[test]
+    fn invalid_records_are_refused() {
+        assert!(Event::decode("a line").is_err());
+        assert!(Event::decode("1;2;tcp\t\tline").is_err());
+        assert!(Event::decode("x;2;tcp;\t\tline").is_err());
+        assert!(Event::decode("1;x;tcp;\t\tline").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use backoff::Backoff;
+use event::Event;
+use futures::Stream;
+use futures::sync::mpsc::{self, Sender};
+use pipe;
+use std::io::{self, BufReader, ErrorKind};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/**
+ * lines read ahead of the buffer from the output of the command
+ */
+const READ_AHEAD: usize = 1024;
+
+/**
+ * a command running at least this long is considered healthy, the restart delay starts again
+ * from the initial one
+ */
+const HEALTHY_RUN: u64 = 10;
+
+/**
+ * The lines written by `command` (run by `sh -c`) on its standard output and error, with the
+ * `stream` field set to `stdout` or `stderr`.
+ *
+ * The command is restarted, after a delay given by `backoff`, every time it exits.
+ */
+pub fn lines(command: String, backoff: Backoff) -> Box<Stream<Item = Event, Error = io::Error>> {
+    let (sender, receiver) = mpsc::channel(READ_AHEAD);
+
+    thread::spawn(move || supervise(&command, backoff, sender));
+
+    Box::new(receiver.map_err(|_| io::Error::new(ErrorKind::Other, "command thread died"))
+        .and_then(|event| event))
+}
+
+fn supervise(command: &str, mut backoff: Backoff, sender: Sender<io::Result<Event>>) {
+    loop {
+        let started = Instant::now();
+        match run(command, sender.clone()) {
+            Ok(false) => return,
+            Ok(true) => {}
+            Err(err) => error!("Command `{}`: {}", command, err),
+        }
+
+        if started.elapsed() >= Duration::from_secs(HEALTHY_RUN) {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        info!("Restarting `{}` in {:?}", command, delay);
+        thread::sleep(delay);
+    }
+}
+
+/**
+ * Runs the command until it exits, returns false when its lines are not wanted anymore
+ */
+fn run(command: &str, sender: Sender<io::Result<Event>>) -> io::Result<bool> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    info!("Started `{}` with pid {}", command, child.id());
+
+    let stderr = child.stderr.take().expect("a piped stderr");
+    let stderr_sender = sender.clone();
+    let stderr_reading = thread::spawn(move || {
+        pipe::read(BufReader::new(stderr),
+                   |line| Event::new(line).received_on("exec", None).with_field("stream", "stderr"),
+                   stderr_sender)
+    });
+
+    let stdout = child.stdout.take().expect("a piped stdout");
+    let stdout_reading = pipe::read(BufReader::new(stdout),
+                                    |line| Event::new(line).received_on("exec", None).with_field("stream", "stdout"),
+                                    sender);
+    let stderr_reading = stderr_reading.join()
+        .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::Other, "stderr reader panicked")));
+
+    let status = child.wait()?;
+    warn!("Command `{}` exited with {}", command, status);
+
+    Ok(stdout_reading? && stderr_reading?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    fn run_lines(command: &str) -> Vec<(String, String)> {
+        let (sender, receiver) = mpsc::channel(READ_AHEAD);
+        assert!(run(command, sender).unwrap());
+        let mut lines = receiver.map(|event| {
+                let event = event.unwrap();
+                (event.fields["stream"].clone(), event.line)
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        lines.sort();
+        lines
+    }
+
+    fn line(stream: &str, line: &str) -> (String, String) {
+        (stream.to_string(), line.to_string())
+    }
+
+    #[test]
+    fn both_outputs_are_read_line_by_line() {
+        let lines = run_lines(r"printf 'out1\r\nout2\n\nlast'; printf 'err1\nerr2\n' >&2");
+        assert_eq!(lines,
+                   [line("stderr", "err1"),
+                    line("stderr", "err2"),
+                    line("stdout", ""),
+                    line("stdout", "last"),
+                    line("stdout", "out1"),
+                    line("stdout", "out2")]);
+    }
+
+    #[test]
+    fn the_command_is_restarted_when_it_exits() {
+        let backoff = Backoff::new(Duration::from_millis(1), 1.0, Duration::from_millis(1), 0.0);
+        let events = lines("echo started".to_string(), backoff).take(3).collect().wait().unwrap();
+        assert!(events.iter().all(|event| event.line == "started" && event.listener == Some("exec".to_string())));
+        assert_eq!(events.len(), 3);
+    }
+}
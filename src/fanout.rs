@@ -0,0 +1,232 @@
+use buffer::BufferSender;
+use event::Event;
+use futures::future::Future;
+use futures::{Async, AsyncSink, Poll, StartSend, Sink, Stream};
+use route::Routing;
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Interval};
+
+/**
+ * A destination of the fan-out: its own buffer, read by its own StubbornSink
+ */
+pub struct Destination {
+    name: String,
+    buftx: BufferSender,
+    accepted: u64,
+    delivered: Rc<Cell<u64>>,
+}
+
+impl Destination {
+    pub fn new(name: String, buftx: BufferSender, delivered: Rc<Cell<u64>>) -> Self {
+        Destination {
+            name: name,
+            buftx: buftx,
+            accepted: 0,
+            delivered: delivered,
+        }
+    }
+
+    /**
+     * lines accepted for this destination that have been neither delivered nor dropped yet
+     */
+    fn lag(&self) -> u64 {
+        self.accepted.saturating_sub(self.delivered.get() + self.buftx.dropped())
+    }
+}
+
+/**
+ * Copies every line to the buffer of each destination, or of the destinations chosen by the
+ * routing.
+ *
+ * Each destination drains its buffer independently, so an unreachable one only fills its own
+ * buffer. With the `block` overflow policy, which never loses a line, a full destination buffer
+ * holds back the others as the shared intake buffer fills up; the other policies keep the
+ * destinations independent.
+ */
+pub struct Broadcast {
+    destinations: Rc<Vec<RefCell<Destination>>>,
+    pending: Vec<Option<Event>>,
+    routing: Option<Routing>,
+}
+
+impl Broadcast {
+    pub fn new(destinations: Vec<Destination>) -> Self {
+        let pending = destinations.iter().map(|_| None).collect();
+
+        Broadcast {
+            destinations: Rc::new(destinations.into_iter().map(RefCell::new).collect()),
+            pending: pending,
+            routing: None,
+        }
+    }
+
+    /**
+     * Sends every line only to the destinations chosen by `routing`
+     */
+    pub fn with_routing(mut self, routing: Routing) -> Self {
+        self.routing = Some(routing);
+        self
+    }
+
+    /**
+     * Logs, every `interval`, how many lines each destination is behind, until the broadcast is
+     * dropped
+     */
+    pub fn report_lag(&self, interval: Duration, handle: &Handle) -> io::Result<Box<Future<Item = (), Error = io::Error>>> {
+        let destinations = Rc::downgrade(&self.destinations);
+        let reporting = Interval::new(interval, handle)?
+            .map(move |_| destinations.upgrade())
+            .take_while(|destinations| Ok(destinations.is_some()))
+            .for_each(|destinations| {
+                for destination in destinations.expect("a live broadcast").iter() {
+                    let destination = destination.borrow();
+                    info!("Destination {} is {} lines behind", destination.name, destination.lag());
+                }
+                Ok(())
+            });
+
+        Ok(Box::new(reporting))
+    }
+
+    fn flush_pending(&mut self) -> Poll<(), io::Error> {
+        let mut ready = true;
+        for (destination, pending) in self.destinations.iter().zip(self.pending.iter_mut()) {
+            if let Some(event) = pending.take() {
+                let mut destination = destination.borrow_mut();
+                match destination.buftx.start_send(event)? {
+                    AsyncSink::Ready => destination.accepted += 1,
+                    AsyncSink::NotReady(event) => {
+                        *pending = Some(event);
+                        ready = false;
+                    }
+                }
+            }
+        }
+
+        if ready {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Sink for Broadcast {
+    type SinkItem = Event;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, event: Event) -> StartSend<Event, io::Error> {
+        if let Async::NotReady = self.flush_pending()? {
+            return Ok(AsyncSink::NotReady(event));
+        }
+
+        let selected = self.routing.as_ref().map(|routing| routing.select(&event));
+        for (index, pending) in self.pending.iter_mut().enumerate() {
+            if selected.as_ref().map(|selected| selected[index]).unwrap_or(true) {
+                *pending = Some(event.clone());
+            }
+        }
+        self.flush_pending()?;
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.flush_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buffer::{self, BufferReceiver, Capacity, OverflowPolicy};
+    use futures::future;
+
+    fn destination(name: &str, lines: Option<usize>, policy: OverflowPolicy) -> (Destination, BufferReceiver) {
+        let capacity = Capacity {
+            lines: lines,
+            bytes: None,
+        };
+        let (buftx, bufrx) = buffer::bounded(capacity, policy, None);
+        (Destination::new(name.to_string(), buftx, Rc::new(Cell::new(0))), bufrx)
+    }
+
+    /**
+     * Sends the lines within a task, as a full buffer with the `block` policy parks the sender
+     */
+    fn send(broadcast: &mut Broadcast, lines: &[&str]) -> Vec<bool> {
+        future::lazy(|| {
+                let sent = lines.iter()
+                    .map(|line| broadcast.start_send(Event::new(line.to_string())).unwrap().is_ready())
+                    .collect::<Vec<_>>();
+                Ok::<_, ()>(sent)
+            })
+            .wait()
+            .unwrap()
+    }
+
+    fn next(bufrx: &mut BufferReceiver) -> String {
+        match bufrx.poll().unwrap() {
+            Async::Ready(Some(event)) => event.line,
+            _ => panic!("nothing received"),
+        }
+    }
+
+    fn received(bufrx: BufferReceiver) -> Vec<String> {
+        bufrx.map(|event| event.line).collect().wait().unwrap()
+    }
+
+    #[test]
+    fn every_line_goes_to_every_destination() {
+        let (first, first_rx) = destination("first", None, OverflowPolicy::Block);
+        let (second, second_rx) = destination("second", None, OverflowPolicy::Block);
+        let mut broadcast = Broadcast::new(vec![first, second]);
+
+        assert_eq!(send(&mut broadcast, &["one", "two", "three"]), [true, true, true]);
+        drop(broadcast);
+
+        assert_eq!(received(first_rx), ["one", "two", "three"]);
+        assert_eq!(received(second_rx), ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn a_lagging_destination_only_loses_its_own_lines() {
+        let (lagging, lagging_rx) = destination("lagging", Some(1), OverflowPolicy::DropNewest);
+        let (healthy, healthy_rx) = destination("healthy", None, OverflowPolicy::Block);
+        let mut broadcast = Broadcast::new(vec![lagging, healthy]);
+
+        assert_eq!(send(&mut broadcast, &["one", "two", "three"]), [true, true, true]);
+        {
+            let lagging = broadcast.destinations[0].borrow();
+            assert_eq!((lagging.accepted, lagging.buftx.dropped(), lagging.lag()), (3, 2, 1));
+            assert_eq!(broadcast.destinations[1].borrow().lag(), 3);
+        }
+        broadcast.destinations[1].borrow().delivered.set(2);
+        assert_eq!(broadcast.destinations[1].borrow().lag(), 1);
+        drop(broadcast);
+
+        assert_eq!(received(lagging_rx), ["one"]);
+        assert_eq!(received(healthy_rx), ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn a_full_blocking_destination_holds_back_the_next_lines() {
+        let (full, mut full_rx) = destination("full", Some(1), OverflowPolicy::Block);
+        let (other, other_rx) = destination("other", None, OverflowPolicy::Block);
+        let mut broadcast = Broadcast::new(vec![full, other]);
+
+        assert_eq!(send(&mut broadcast, &["one", "two", "three"]), [true, true, false]);
+
+        assert_eq!(next(&mut full_rx), "one");
+        assert_eq!(send(&mut broadcast, &["three"]), [true]);
+        assert_eq!(next(&mut full_rx), "two");
+        assert!(future::lazy(|| Ok::<_, ()>(broadcast.poll_complete().unwrap().is_ready())).wait().unwrap());
+        drop(broadcast);
+
+        assert_eq!(received(full_rx), ["three"]);
+        assert_eq!(received(other_rx), ["one", "two", "three"]);
+    }
+}
@@ -0,0 +1,347 @@
+use enrich::Path;
+use event::Event;
+use futures::{Future, Stream};
+use regex::Regex;
+use serde_json::{self, Value};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Interval};
+
+/**
+ * operators of the field comparisons, the two characters ones first so that `<=` is not read as `<`
+ */
+const OPERATORS: [(&'static str, Operator); 7] = [("==", Operator::Equal),
+                                                  ("!=", Operator::NotEqual),
+                                                  ("<=", Operator::LessOrEqual),
+                                                  (">=", Operator::GreaterOrEqual),
+                                                  ("=~", Operator::Matches),
+                                                  ("<", Operator::Less),
+                                                  (">", Operator::Greater)];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Include,
+    Exclude,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Matches,
+}
+
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /**
+     * a regular expression searched in the raw line
+     */
+    Line(Regex),
+    /**
+     * a field of the line parsed as a JSON object, or else a metadata field of the event, compared
+     * to a value; the value is JSON when it parses as such (`100`, `true`, `"text"`) and a string
+     * otherwise, or a regular expression for `=~`
+     */
+    Field(Path, Operator, Value, Option<Regex>),
+}
+
+impl Matcher {
+    /**
+     * `PATH OP VALUE` where OP is one of `==`, `!=`, `<`, `<=`, `>`, `>=` or `=~`
+     */
+    pub fn field(comparison: &str) -> Result<Self, String> {
+        let (position, symbol, operator) = comparison.char_indices()
+            .filter_map(|(position, _)| {
+                OPERATORS.iter()
+                    .find(|&&(symbol, _)| comparison[position..].starts_with(symbol))
+                    .map(|&(symbol, operator)| (position, symbol, operator))
+            })
+            .next()
+            .ok_or_else(|| format!("`{}` is not a field comparison", comparison))?;
+
+        let path = comparison[..position].parse()?;
+        let value = comparison[position + symbol.len()..].trim();
+        let regex = if operator == Operator::Matches {
+            Some(Regex::new(value).map_err(|err| format!("invalid regular expression `{}`: {}", value, err))?)
+        } else {
+            None
+        };
+        Ok(Matcher::Field(path, operator, parse_value(value), regex))
+    }
+
+    pub fn line(regex: &str) -> Result<Self, String> {
+        Regex::new(regex)
+            .map(Matcher::Line)
+            .map_err(|err| format!("invalid regular expression `{}`: {}", regex, err))
+    }
+
+    /**
+     * `json` is the line parsed as JSON, when it is
+     */
+    pub fn matches(&self, event: &Event, json: Option<&Value>) -> bool {
+        match *self {
+            Matcher::Line(ref regex) => regex.is_match(&event.line),
+            Matcher::Field(ref path, operator, ref expected, ref regex) => {
+                let actual = match json {
+                    Some(&Value::Object(ref object)) => path.get(object),
+                    _ => None,
+                };
+                /**
+                 * a metadata field is read as the value it is compared to, and a field missing from
+                 * both matches no comparison, not even `!=`
+                 */
+                let metadata;
+                let actual = match actual {
+                    Some(actual) => actual,
+                    None => {
+                        match path.get_field(&event.fields) {
+                            Some(value) => {
+                                metadata = parse_value(value);
+                                &metadata
+                            }
+                            None => return false,
+                        }
+                    }
+                };
+
+                match operator {
+                    Operator::Equal => actual == expected,
+                    Operator::NotEqual => actual != expected,
+                    Operator::Matches => {
+                        let text = match *actual {
+                            Value::String(ref text) => text.clone(),
+                            ref other => other.to_string(),
+                        };
+                        regex.as_ref().map(|regex| regex.is_match(&text)).unwrap_or(false)
+                    }
+                    Operator::Less => compare(actual, expected) == Some(Ordering::Less),
+                    Operator::LessOrEqual => compare(actual, expected).map(|order| order != Ordering::Greater).unwrap_or(false),
+                    Operator::Greater => compare(actual, expected) == Some(Ordering::Greater),
+                    Operator::GreaterOrEqual => compare(actual, expected).map(|order| order != Ordering::Less).unwrap_or(false),
+                }
+            }
+        }
+    }
+}
+
+/**
+ * JSON when it parses as such, a string otherwise
+ */
+fn parse_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+/**
+ * Numbers are compared with numbers and strings with strings, anything else is not comparable
+ */
+fn compare(actual: &Value, expected: &Value) -> Option<Ordering> {
+    match (actual, expected) {
+        (&Value::Number(ref actual), &Value::Number(ref expected)) => {
+            actual.as_f64().and_then(|actual| expected.as_f64().and_then(|expected| actual.partial_cmp(&expected)))
+        }
+        (&Value::String(ref actual), &Value::String(ref expected)) => Some(actual.cmp(expected)),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub matcher: Matcher,
+    /**
+     * the rule as it was given, to report its hits
+     */
+    pub description: String,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.action {
+            Action::Include => "include",
+            Action::Exclude => "exclude",
+        };
+        write!(f, "{} `{}`", action, self.description)
+    }
+}
+
+/**
+ * Decides which events are buffered: an event matching any exclude rule is dropped, and when there
+ * are include rules an event has to match one of them to be kept.
+ *
+ * Every rule counts the events it matched, an event being counted by the first rule which decided
+ * its fate.
+ */
+#[derive(Clone)]
+pub struct Filter {
+    rules: Rc<Vec<Rule>>,
+    hits: Rc<Vec<Cell<u64>>>,
+    /**
+     * the line is parsed only when a rule looks at its fields
+     */
+    parse_json: bool,
+    has_includes: bool,
+    /**
+     * events dropped because they matched no include rule
+     */
+    unmatched: Rc<Cell<u64>>,
+}
+
+impl Filter {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let hits = rules.iter().map(|_| Cell::new(0)).collect();
+        let parse_json = rules.iter().any(|rule| match rule.matcher {
+            Matcher::Field(..) => true,
+            Matcher::Line(_) => false,
+        });
+        let has_includes = rules.iter().any(|rule| rule.action == Action::Include);
+        Filter {
+            rules: Rc::new(rules),
+            hits: Rc::new(hits),
+            parse_json: parse_json,
+            has_includes: has_includes,
+            unmatched: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn accepts(&self, event: &Event) -> bool {
+        let json = if self.parse_json {
+            serde_json::from_str(&event.line).ok()
+        } else {
+            None
+        };
+
+        for action in &[Action::Exclude, Action::Include] {
+            for (rule, hits) in self.rules.iter().zip(self.hits.iter()) {
+                if rule.action == *action && rule.matcher.matches(event, json.as_ref()) {
+                    hits.set(hits.get() + 1);
+                    return rule.action == Action::Include;
+                }
+            }
+        }
+
+        if self.has_includes {
+            self.unmatched.set(self.unmatched.get() + 1);
+        }
+        !self.has_includes
+    }
+
+    /**
+     * Logs, every `interval`, how many events each rule matched
+     */
+    pub fn report_hits(&self, interval: Duration, handle: &Handle) -> io::Result<Box<Future<Item = (), Error = io::Error>>> {
+        let filter = self.clone();
+        let reporting = Interval::new(interval, handle)?.for_each(move |_| {
+            for (rule, hits) in filter.rules.iter().zip(filter.hits.iter()) {
+                info!("Filter rule {} matched {} events", rule, hits.get());
+            }
+            if filter.has_includes {
+                info!("{} events matched no include rule", filter.unmatched.get());
+            }
+            Ok(())
+        });
+
+        Ok(Box::new(reporting))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operator_of(comparison: &str) -> (Path, Operator, Value) {
+        match Matcher::field(comparison).unwrap() {
+            Matcher::Field(path, operator, value, _) => (path, operator, value),
+            Matcher::Line(_) => panic!("a line matcher for `{}`", comparison),
+        }
+    }
+
+    fn matches(comparison: &str, line: &str) -> bool {
+        let event = Event::new(line.to_string());
+        let json = serde_json::from_str::<Value>(line).ok();
+        Matcher::field(comparison).unwrap().matches(&event, json.as_ref())
+    }
+
+    #[test]
+    fn two_characters_operators_are_not_read_as_one() {
+        assert_eq!(operator_of("level<=3"), operator_of("level <= 3"));
+        assert_eq!(operator_of("level<=3").1, Operator::LessOrEqual);
+        assert_eq!(operator_of("level>=3").1, Operator::GreaterOrEqual);
+        assert_eq!(operator_of("level<3").1, Operator::Less);
+        assert_eq!(operator_of("level>3").1, Operator::Greater);
+        assert_eq!(operator_of("level==3").1, Operator::Equal);
+        assert_eq!(operator_of("level!=3").1, Operator::NotEqual);
+        assert_eq!(operator_of("message=~^error").1, Operator::Matches);
+    }
+
+    #[test]
+    fn field_path_and_value_are_split_at_the_first_operator() {
+        let (path, _, value) = operator_of("@fields.level<=3");
+        assert_eq!(path, "@fields.level".parse().unwrap());
+        assert_eq!(value, Value::from(3));
+
+        let (_, operator, value) = operator_of("message=~a<=b");
+        assert_eq!(operator, Operator::Matches);
+        assert_eq!(value, Value::from("a<=b"));
+
+        let (_, _, value) = operator_of("level==\"3\"");
+        assert_eq!(value, Value::from("3"));
+    }
+
+    #[test]
+    fn invalid_comparisons_are_refused() {
+        assert!(Matcher::field("level").is_err());
+        assert!(Matcher::field("==3").is_err());
+        assert!(Matcher::field("message=~(").is_err());
+    }
+
+    #[test]
+    fn numbers_compare_as_numbers() {
+        assert!(matches("level>9", r#"{"level":10}"#));
+        assert!(matches("level<=10", r#"{"level":10}"#));
+        assert!(!matches("level<10", r#"{"level":10}"#));
+        assert!(matches("level>=1.5", r#"{"level":2}"#));
+        assert!(matches("level==10", r#"{"level":10}"#));
+    }
+
+    #[test]
+    fn strings_compare_as_strings() {
+        assert!(matches("name<b", r#"{"name":"a"}"#));
+        assert!(matches("name>=abc", r#"{"name":"abd"}"#));
+        assert!(matches("name==alice", r#"{"name":"alice"}"#));
+        assert!(matches("name=~^al", r#"{"name":"alice"}"#));
+        assert!(matches("level=~^1", r#"{"level":10}"#));
+    }
+
+    #[test]
+    fn numbers_and_strings_do_not_compare() {
+        assert!(!matches("level<\"20\"", r#"{"level":10}"#));
+        assert!(!matches("level>5", r#"{"level":"10"}"#));
+        assert!(!matches("level==10", r#"{"level":"10"}"#));
+    }
+
+    #[test]
+    fn missing_fields_match_nothing() {
+        assert!(!matches("level!=3", r#"{"other":3}"#));
+        assert!(!matches("level==3", "not json"));
+        assert!(!matches("a.b==3", r#"{"a":3}"#));
+        assert!(matches("a.b==3", r#"{"a":{"b":3}}"#));
+    }
+
+    #[test]
+    fn metadata_fields_are_compared_when_the_line_has_no_such_field() {
+        let event = Event::new("not json".to_string()).with_field("path", "/var/log/app.log").with_field("level", "4");
+        assert!(Matcher::field("path=~app").unwrap().matches(&event, None));
+        assert!(Matcher::field("level>3").unwrap().matches(&event, None));
+
+        let json = serde_json::from_str::<Value>(r#"{"level":2}"#).unwrap();
+        assert!(!Matcher::field("level>3").unwrap().matches(&event, Some(&json)));
+    }
+}
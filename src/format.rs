@@ -0,0 +1,216 @@
+use chrono::{DateTime, Utc};
+use event::Event;
+use resolve;
+use serde_json::{Map, Value};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Literal(String),
+    Line,
+    ReceivedAt,
+    Peer,
+    Listener,
+    Sequence,
+    Field(String),
+}
+
+/**
+ * How an event is written to the destinations
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Format {
+    /**
+     * the line exactly as it was received
+     */
+    Raw,
+    /**
+     * `{"message":LINE,"host":PEER HOST,"@timestamp":RECEIVED AT}`, with the fields of the event
+     * under `fields`
+     */
+    Json,
+    /**
+     * text where `{line}`, `{received_at}`, `{peer}`, `{listener}` and `{sequence}` are replaced by
+     * the event metadata, `{hostname}` by the name of this host and any other `{NAME}` by the field
+     * NAME, `{{` being a literal `{`
+     */
+    Template(Vec<Segment>),
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, String> {
+        match format {
+            "raw" => Ok(Format::Raw),
+            "json" => Ok(Format::Json),
+            template => parse_template(template).map(Format::Template),
+        }
+    }
+}
+
+impl Format {
+    pub fn render(&self, event: &Event) -> String {
+        match *self {
+            Format::Raw => event.line.clone(),
+            Format::Json => render_json(event),
+            Format::Template(ref segments) => {
+                let mut rendered = String::new();
+                for segment in segments {
+                    match *segment {
+                        Segment::Literal(ref text) => rendered.push_str(text),
+                        Segment::Line => rendered.push_str(&event.line),
+                        Segment::ReceivedAt => rendered.push_str(&timestamp(event)),
+                        Segment::Peer => rendered.push_str(event.peer.as_ref().map(|peer| &peer[..]).unwrap_or("")),
+                        Segment::Listener => {
+                            rendered.push_str(event.listener.as_ref().map(|listener| &listener[..]).unwrap_or(""))
+                        }
+                        Segment::Sequence => rendered.push_str(&event.sequence.to_string()),
+                        Segment::Field(ref name) => {
+                            rendered.push_str(event.fields.get(name).map(|value| &value[..]).unwrap_or(""))
+                        }
+                    }
+                }
+                rendered
+            }
+        }
+    }
+}
+
+pub fn parse_template(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("unterminated placeholder in format `{}`", template)),
+                    }
+                }
+                if name.trim().is_empty() {
+                    return Err(format!("empty placeholder in format `{}`", template));
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(literal.clone()));
+                    literal.clear();
+                }
+                segments.push(match name.trim() {
+                    "line" => Segment::Line,
+                    "received_at" => Segment::ReceivedAt,
+                    "peer" => Segment::Peer,
+                    "listener" => Segment::Listener,
+                    "sequence" => Segment::Sequence,
+                    "hostname" => Segment::Literal(hostname()),
+                    field => Segment::Field(field.to_string()),
+                });
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    Ok(segments)
+}
+
+/**
+ * The name of this host, as the kernel knows it
+ */
+fn hostname() -> String {
+    let mut hostname = String::new();
+    match File::open("/proc/sys/kernel/hostname").and_then(|mut file| file.read_to_string(&mut hostname)) {
+        Ok(_) => hostname.trim().to_string(),
+        Err(_) => env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string()),
+    }
+}
+
+/**
+ * RFC 3339, in UTC
+ */
+fn timestamp(event: &Event) -> String {
+    DateTime::<Utc>::from(event.received_at).to_rfc3339()
+}
+
+fn render_json(event: &Event) -> String {
+    let mut object = Map::new();
+    object.insert("message".to_string(), Value::String(event.line.clone()));
+    if let Some(ref peer) = event.peer {
+        object.insert("host".to_string(), Value::String(resolve::host(peer).to_string()));
+    }
+    object.insert("@timestamp".to_string(), Value::String(timestamp(event)));
+    if !event.fields.is_empty() {
+        let fields = event.fields
+            .iter()
+            .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+            .collect();
+        object.insert("fields".to_string(), Value::Object(fields));
+    }
+
+    Value::Object(object).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal(text: &str) -> Segment {
+        Segment::Literal(text.to_string())
+    }
+
+    #[test]
+    fn placeholders_are_parsed_between_literals() {
+        assert_eq!(parse_template("{listener} {peer}: {line} #{ sequence }").unwrap(),
+                   [Segment::Listener, literal(" "), Segment::Peer, literal(": "), Segment::Line, literal(" #"),
+                    Segment::Sequence]);
+        assert_eq!(parse_template("{received_at}{level}").unwrap(),
+                   [Segment::ReceivedAt, Segment::Field("level".to_string())]);
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(parse_template("{{line}}").unwrap(), [literal("{line}")]);
+        assert_eq!(parse_template("{{{line}}}").unwrap(), [literal("{"), Segment::Line, literal("}")]);
+        assert_eq!(parse_template("a {{ b }} c").unwrap(), [literal("a { b } c")]);
+    }
+
+    #[test]
+    fn a_single_closing_brace_is_literal() {
+        assert_eq!(parse_template("a}b").unwrap(), [literal("a}b")]);
+        assert_eq!(parse_template("{line}}").unwrap(), [Segment::Line, literal("}")]);
+    }
+
+    #[test]
+    fn invalid_placeholders_are_refused() {
+        assert!(parse_template("{line").is_err());
+        assert!(parse_template("{}").is_err());
+        assert!(parse_template("{ }").is_err());
+        assert!(parse_template("{{{line").is_err());
+    }
+
+    #[test]
+    fn templates_are_rendered_with_the_event() {
+        let format = "{{{listener}}} {peer} {level}{missing}: {line}".parse::<Format>().unwrap();
+        let event = Event::new("hello".to_string())
+            .received_on("tcp", Some("10.0.0.1:4000".to_string()))
+            .with_field("level", "info");
+        assert_eq!(format.render(&event), "{tcp} 10.0.0.1:4000 info: hello");
+    }
+}
@@ -0,0 +1,482 @@
+use buffer::BufferSender;
+use event::{Event, Receipt};
+use futures::{AsyncSink, Sink, Stream};
+use futures::future::{self, Future};
+use std::io;
+use std::str;
+use tokio_core::io::{Codec, EasyBuf, Io};
+use tokio_core::reactor::Handle;
+
+/**
+ * larger requests are refused, the whole body is held in memory before being accepted
+ */
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/**
+ * seconds a client is asked to wait before retrying while the buffer is full
+ */
+const RETRY_AFTER: u64 = 1;
+
+pub struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+pub struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn new(status: u16, reason: &'static str, body: String) -> Self {
+        Response {
+            status: status,
+            reason: reason,
+            headers: vec![],
+            body: body,
+        }
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/**
+ * What is read from a connection
+ */
+pub enum Incoming {
+    Request(Request),
+    /**
+     * the client waits for `100 Continue` before sending the body
+     */
+    Continue,
+    /**
+     * the request cannot be read: it is answered and the connection is closed
+     */
+    Refused(Response),
+    /**
+     * nothing is read after a refused request
+     */
+    Closed,
+}
+
+/**
+ * HTTP/1.1 requests with a `Content-Length` body, chunked bodies are not supported
+ */
+pub struct HttpCodec {
+    /**
+     * `100 Continue` has been asked for the request being read
+     */
+    continued: bool,
+    refused: bool,
+}
+
+impl HttpCodec {
+    pub fn new() -> Self {
+        HttpCodec {
+            continued: false,
+            refused: false,
+        }
+    }
+
+    fn decode_request(&mut self, buf: &mut EasyBuf) -> Result<Option<Incoming>, Response> {
+        let head_length = match buf.as_slice().windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(position) if position + 4 <= MAX_HEAD_SIZE => position + 4,
+            Some(_) => return Err(refused(431, "Request Header Fields Too Large", "request head is too large")),
+            None if buf.len() >= MAX_HEAD_SIZE => {
+                return Err(refused(431, "Request Header Fields Too Large", "request head is too large"))
+            }
+            None => return Ok(None),
+        };
+
+        let (method, path, content_length, expect_continue) = {
+            let head = str::from_utf8(&buf.as_slice()[..head_length])
+                .map_err(|_| refused(400, "Bad Request", "request head is not UTF-8"))?;
+            let mut lines = head.split("\r\n");
+
+            let mut request_line = lines.next().unwrap_or("").split(' ');
+            let method = request_line.next().unwrap_or("").to_string();
+            let path = request_line.next().unwrap_or("").to_string();
+
+            let mut content_length = 0;
+            let mut expect_continue = false;
+            for header in lines.filter(|line| !line.is_empty()) {
+                let mut parts = header.splitn(2, ':');
+                let name = parts.next().unwrap_or("").trim().to_lowercase();
+                let value = parts.next().unwrap_or("").trim();
+                match name.as_str() {
+                    "content-length" => {
+                        content_length = value.parse()
+                            .map_err(|_| refused(400, "Bad Request", "invalid Content-Length"))?;
+                    }
+                    "transfer-encoding" => {
+                        return Err(refused(501, "Not Implemented", "chunked requests are not supported"));
+                    }
+                    "expect" if value.eq_ignore_ascii_case("100-continue") => expect_continue = true,
+                    _ => {}
+                }
+            }
+
+            (method, path, content_length, expect_continue)
+        };
+
+        if content_length > MAX_BODY_SIZE {
+            return Err(refused(413, "Payload Too Large", "request body is too large"));
+        }
+        if buf.len() < head_length + content_length {
+            if expect_continue && !self.continued {
+                self.continued = true;
+                return Ok(Some(Incoming::Continue));
+            }
+            return Ok(None);
+        }
+
+        buf.drain_to(head_length);
+        let body = buf.drain_to(content_length).as_slice().to_vec();
+        self.continued = false;
+
+        Ok(Some(Incoming::Request(Request {
+            method: method,
+            path: path,
+            body: body,
+        })))
+    }
+}
+
+impl Codec for HttpCodec {
+    type In = Incoming;
+    type Out = Response;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Incoming>> {
+        if self.refused {
+            let length = buf.len();
+            buf.drain_to(length);
+            return Ok(Some(Incoming::Closed));
+        }
+
+        match self.decode_request(buf) {
+            Ok(incoming) => Ok(incoming),
+            Err(response) => {
+                self.refused = true;
+                Ok(Some(Incoming::Refused(response)))
+            }
+        }
+    }
+
+    /**
+     * Interim responses, like `100 Continue`, have neither headers nor body
+     */
+    fn encode(&mut self, response: Response, buf: &mut Vec<u8>) -> io::Result<()> {
+        buf.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", response.status, response.reason).as_bytes());
+        if response.status < 200 {
+            buf.extend_from_slice(b"\r\n");
+            return Ok(());
+        }
+        for (name, value) in response.headers {
+            buf.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        buf.extend_from_slice(format!("Content-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                                      response.body.len())
+            .as_bytes());
+        buf.extend_from_slice(response.body.as_bytes());
+        Ok(())
+    }
+}
+
+/**
+ * The answer to a request which cannot be read, the connection is closed after it
+ */
+fn refused(status: u16, reason: &'static str, error: &str) -> Response {
+    Response::new(status, reason, format!(r#"{{"error":"{}"}}"#, error)).with_header("Connection", "close".to_string())
+}
+
+type Reply = Box<Future<Item = Response, Error = io::Error>>;
+
+/**
+ * Serves `POST /ingest` on `socket`: every non empty line of the body is a record.
+ *
+ * A batch is accepted as a whole or not at all: 202 once every record is in the buffer, 503 when
+ * the buffer has no room for it. With `persisted`, when the records go through the spool, the
+ * 202 waits for the whole batch to be synced to it. A request which cannot be read is answered
+ * with an error and ends the connection.
+ */
+pub fn process_connection<S: Io + 'static>(socket: S,
+                                           peer: String,
+                                           buftx: &BufferSender,
+                                           persisted: bool,
+                                           handle: &Handle) {
+    let (responses, requests) = socket.framed(HttpCodec::new()).split();
+
+    let mut buftx = buftx.clone();
+    let client = peer.clone();
+    let serving = requests.take_while(|incoming| {
+            Ok(match *incoming {
+                Incoming::Closed => false,
+                _ => true,
+            })
+        })
+        .and_then(move |incoming| match incoming {
+            Incoming::Request(request) => ingest(request, &client, &mut buftx, persisted),
+            Incoming::Continue => reply(Response::new(100, "Continue", String::new())),
+            Incoming::Refused(response) => {
+                warn!("HTTP client {}: {}", client, response.body);
+                reply(response)
+            }
+            Incoming::Closed => unreachable!("the requests end before"),
+        })
+        .forward(responses)
+        .map(|_| ())
+        .map_err(move |err| warn!("HTTP client {}: {}", peer, err));
+
+    handle.spawn(serving);
+}
+
+fn ingest(request: Request, peer: &str, buftx: &mut BufferSender, persisted: bool) -> Reply {
+    if request.path != "/ingest" {
+        return reply(Response::new(404, "Not Found", r#"{"error":"not found"}"#.to_string()));
+    }
+    if request.method != "POST" {
+        return reply(Response::new(405, "Method Not Allowed", r#"{"error":"only POST is allowed"}"#.to_string())
+            .with_header("Allow", "POST".to_string()));
+    }
+
+    let (receipt, persisting) = Receipt::new();
+    let events = String::from_utf8_lossy(&request.body)
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let event = Event::new(line.to_string()).received_on("http", Some(peer.to_string()));
+            if persisted {
+                event.with_receipt(receipt.clone())
+            } else {
+                event
+            }
+        })
+        .collect::<Vec<_>>();
+    /**
+     * the receipt completes once the events are done with
+     */
+    drop(receipt);
+
+    if !buftx.has_room_for(&events) {
+        return reply(unavailable());
+    }
+
+    let count = events.len();
+    for event in events {
+        /**
+         * the buffer has room for the whole batch, nothing else runs on the event loop meanwhile
+         */
+        match buftx.start_send(event) {
+            Ok(AsyncSink::Ready) => {}
+            Ok(AsyncSink::NotReady(_)) => {
+                warn!("The buffer refused a record of an HTTP batch it had room for, part of the batch is buffered");
+                return reply(unavailable());
+            }
+            Err(err) => {
+                error!("Cannot buffer an HTTP record: {}", err);
+                return reply(Response::new(500,
+                                           "Internal Server Error",
+                                           r#"{"error":"cannot buffer the records"}"#.to_string()));
+            }
+        }
+    }
+
+    let accepted = Response::new(202, "Accepted", format!(r#"{{"accepted":{}}}"#, count));
+    if !persisted {
+        return reply(accepted);
+    }
+
+    Box::new(persisting.then(move |written| match written {
+        Ok(true) => Ok(accepted),
+        _ => {
+            Ok(Response::new(500,
+                             "Internal Server Error",
+                             r#"{"error":"cannot spool the records"}"#.to_string()))
+        }
+    }))
+}
+
+fn reply(response: Response) -> Reply {
+    Box::new(future::ok(response))
+}
+
+fn unavailable() -> Response {
+    Response::new(503, "Service Unavailable", r#"{"error":"buffer is full"}"#.to_string())
+        .with_header("Retry-After", RETRY_AFTER.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buffer::{self, Capacity, OverflowPolicy};
+    use futures::Future;
+
+    /**
+     * Feeds the codec with `chunks` as they would arrive from the socket
+     */
+    fn decode<T: AsRef<[u8]>>(chunks: &[T]) -> Vec<Incoming> {
+        let mut codec = HttpCodec::new();
+        let mut buf = EasyBuf::new();
+        let mut decoded = vec![];
+        for chunk in chunks {
+            buf.get_mut().extend_from_slice(chunk.as_ref());
+            while let Some(incoming) = codec.decode(&mut buf).unwrap() {
+                let closed = match incoming {
+                    Incoming::Closed => true,
+                    _ => false,
+                };
+                decoded.push(incoming);
+                if closed {
+                    return decoded;
+                }
+            }
+        }
+        decoded
+    }
+
+    fn request(incoming: &Incoming) -> (&str, &str, &str) {
+        match *incoming {
+            Incoming::Request(ref request) => {
+                (&request.method, &request.path, str::from_utf8(&request.body).unwrap())
+            }
+            _ => panic!("not a request"),
+        }
+    }
+
+    fn refusal(incoming: &Incoming) -> u16 {
+        match *incoming {
+            Incoming::Refused(ref response) => response.status,
+            _ => panic!("not a refusal"),
+        }
+    }
+
+    fn encode(response: Response) -> String {
+        let mut buf = vec![];
+        HttpCodec::new().encode(response, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn the_head_is_parsed() {
+        let decoded = decode(&["POST /ingest HTTP/1.1\r\nHost: localhost\r\nCONTENT-LENGTH: 3\r\n\r\nabc"]);
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(request(&decoded[0]), ("POST", "/ingest", "abc"));
+    }
+
+    #[test]
+    fn requests_without_a_body_are_read_at_the_end_of_the_head() {
+        let decoded = decode(&["GET /ingest HTTP/1.1\r\n\r\nGET /other HTTP/1.1\r\n\r\n"]);
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(request(&decoded[0]), ("GET", "/ingest", ""));
+        assert_eq!(request(&decoded[1]), ("GET", "/other", ""));
+    }
+
+    #[test]
+    fn the_body_is_waited_for_up_to_the_content_length() {
+        let decoded = decode(&["POST /ingest HTTP/1.1\r\nContent-", "Length: 10\r\n\r\nfirst", "\nsec", "ond\nPOST"]);
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(request(&decoded[0]), ("POST", "/ingest", "first\nseco"));
+    }
+
+    #[test]
+    fn an_endless_head_is_refused() {
+        let header = format!("X-Padding: {}\r\n", "x".repeat(1000));
+        let mut chunks = vec!["POST /ingest HTTP/1.1\r\n"];
+        for _ in 0..20 {
+            chunks.push(&header);
+        }
+
+        assert_eq!(refusal(&decode(&chunks)[0]), 431);
+    }
+
+    #[test]
+    fn unreadable_heads_are_refused() {
+        assert_eq!(refusal(&decode(&["POST /ingest HTTP/1.1\r\nContent-Length: ten\r\n\r\n"])[0]), 400);
+        assert_eq!(refusal(&decode(&[b"POST /ingest\xff HTTP/1.1\r\n\r\n"])[0]), 400);
+        assert_eq!(refusal(&decode(&["POST /ingest HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"])[0]), 501);
+        assert_eq!(refusal(&decode(&["POST /ingest HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n"])[0]), 413);
+    }
+
+    #[test]
+    fn nothing_is_read_after_a_refusal() {
+        let decoded = decode(&["POST /ingest HTTP/1.1\r\nContent-Length: ten\r\n\r\n", "GET / HTTP/1.1\r\n\r\n"]);
+        assert_eq!(decoded.len(), 2);
+        match decoded[1] {
+            Incoming::Closed => {}
+            _ => panic!("the connection is not closed"),
+        }
+    }
+
+    #[test]
+    fn the_body_is_asked_for_once_when_expected() {
+        let decoded = decode(&["POST /ingest HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n",
+                               "ab",
+                               "cd"]);
+        assert_eq!(decoded.len(), 2);
+        match decoded[0] {
+            Incoming::Continue => {}
+            _ => panic!("100 Continue is not sent"),
+        }
+        assert_eq!(request(&decoded[1]), ("POST", "/ingest", "abcd"));
+
+        let decoded = decode(&["POST /ingest HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nab"]);
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(request(&decoded[0]), ("POST", "/ingest", "ab"));
+    }
+
+    #[test]
+    fn interim_responses_have_no_headers() {
+        assert_eq!(encode(Response::new(100, "Continue", String::new())), "HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(encode(Response::new(202, "Accepted", "{}".to_string())),
+                   "HTTP/1.1 202 Accepted\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}");
+    }
+
+    #[test]
+    fn batches_are_accepted_while_the_buffer_has_room() {
+        let capacity = Capacity {
+            lines: Some(3),
+            bytes: None,
+        };
+        let (mut buftx, _bufrx) = buffer::bounded(capacity, OverflowPolicy::Block, None);
+        let post = |body: &str| {
+            Request {
+                method: "POST".to_string(),
+                path: "/ingest".to_string(),
+                body: body.as_bytes().to_vec(),
+            }
+        };
+
+        let accepted = ingest(post("one\r\n\ntwo\n"), "127.0.0.1:1", &mut buftx, false).wait().unwrap();
+        assert_eq!((accepted.status, &accepted.body[..]), (202, r#"{"accepted":2}"#));
+
+        let refused = ingest(post("three\nfour\n"), "127.0.0.1:1", &mut buftx, false).wait().unwrap();
+        assert_eq!(refused.status, 503);
+        assert!(refused.headers.contains(&("Retry-After", RETRY_AFTER.to_string())));
+
+        let accepted = ingest(post("three\n"), "127.0.0.1:1", &mut buftx, false).wait().unwrap();
+        assert_eq!(accepted.status, 202);
+    }
+
+    #[test]
+    fn other_paths_and_methods_are_refused() {
+        let (mut buftx, _bufrx) = buffer::bounded(Capacity::default(), OverflowPolicy::Block, None);
+        let request = |method: &str, path: &str| {
+            Request {
+                method: method.to_string(),
+                path: path.to_string(),
+                body: vec![],
+            }
+        };
+
+        assert_eq!(ingest(request("POST", "/"), "", &mut buftx, false).wait().unwrap().status, 404);
+        assert_eq!(ingest(request("GET", "/ingest"), "", &mut buftx, false).wait().unwrap().status, 405);
+    }
+}
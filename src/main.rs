@@ -2,24 +2,93 @@
 extern crate log;
 extern crate env_logger;
 
+#[macro_use]
 extern crate futures;
 extern crate tokio_core;
-extern crate tokio_line;
 extern crate getopts;
 extern crate tokio_timer;
+extern crate rand;
+extern crate regex;
+extern crate openssl;
+extern crate tokio_openssl;
+extern crate tokio_uds;
+extern crate glob;
+extern crate chrono;
+extern crate serde_json;
 
 mod stubborn_sink;
 mod server;
+mod spool;
+mod backoff;
+mod buffer;
+mod fanout;
+mod balance;
+mod resolve;
+mod tls;
+mod event;
+mod udp;
+mod unix;
+mod syslog;
+mod http;
+mod tail;
+mod pipe;
+mod exec;
+mod codec;
+mod format;
+mod enrich;
+mod filter;
+mod route;
 
-use futures::future::Future;
-use futures::sync::mpsc;
-use futures::Stream;
-use getopts::Options;
+use futures::future::{self, Future};
+use futures::{Sink, Stream};
+use getopts::{Matches, Options};
 use std::env;
+use std::fs;
+use std::str::FromStr;
 use stubborn_sink::StubbornSink;
-use std::io::{self, ErrorKind};
 use server::Server;
-use tokio_core::reactor::Core;
+use spool::Spool;
+use backoff::Backoff;
+use buffer::{BufferSender, Capacity, OverflowPolicy};
+use fanout::{Broadcast, Destination};
+use balance::{Balancer, Strategy};
+use tls::{TlsAcceptor, TlsConnector, Verification};
+use event::Event;
+use codec::{Framing, OversizePolicy, RecordLimit};
+use format::Format;
+use enrich::{Enrichment, NonJson};
+use filter::{Action, Filter, Matcher, Rule};
+use route::{RouteMode, Routing};
+use std::time::Duration;
+use tokio_core::reactor::{Core, Handle};
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::io;
+
+const DEFAULT_SPOOL_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_RECONNECT_DELAY: u64 = 100;
+const DEFAULT_RECONNECT_MULTIPLIER: f64 = 2.0;
+const DEFAULT_RECONNECT_MAX_DELAY: u64 = 30000;
+const DEFAULT_RECONNECT_JITTER: f64 = 0.2;
+const DEFAULT_MAX_IN_FLIGHT: usize = 10000;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10000;
+const DEFAULT_ACK_TIMEOUT: u64 = 30000;
+const DEFAULT_FAILOVER_AFTER: u32 = 3;
+const DEFAULT_FAILBACK_INTERVAL: u64 = 10000;
+const DEFAULT_LAG_REPORT_INTERVAL: u64 = 60000;
+const DEFAULT_FILTER_REPORT_INTERVAL: u64 = 60000;
+const DEFAULT_OVERSIZE_REPORT_INTERVAL: u64 = 60000;
+const DEFAULT_DROP_REPORT_INTERVAL: u64 = 60000;
+const DEFAULT_TAIL_INTERVAL: u64 = 1000;
+/**
+ * lines waiting to be copied to the destination buffers, when there are several destinations
+ */
+const INTAKE_BUFFER_LINES: usize = 1024;
+const TAIL_STATE_FILE: &'static str = "tail.state";
+/**
+ * names of the stream listeners, to set options of a single one
+ */
+const STREAM_LISTENERS: [&'static str; 3] = ["tcp", "tls", "unix"];
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} FILE [options]", program);
@@ -34,52 +103,369 @@ fn main() {
         None => return,
     };
 
-    /**
-     * every connected client receives a clone of buftx where to sends data
-     * all received data are read from bufrx and sent the StubbornSink which will try to
-     * sent it to the final destination. If the final destination is unreachable StubbornSink will
-     * returns a NotReady error and received data will remains in this channel
-     */
-    let (buftx, bufrx) = mpsc::unbounded();
-
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let stubborn_sink = StubbornSink::new(configuration.connect_to.parse().unwrap(),
-                                          handle.clone());
+    let (buftx, delivery) = if configuration.connect_to.len() == 1 && configuration.routing.is_none() {
+        let stubborn_sink = new_stubborn_sink(&configuration, 0, &handle);
+        start_delivery(&configuration, None, stubborn_sink, &handle)
+    } else if let Some(ref strategy) = configuration.balance {
+        /**
+         * load balancing: lines are spread over the destinations, sharing a single buffer
+         */
+        let members = (0..configuration.connect_to.len())
+            .map(|index| new_stubborn_sink(&configuration, index, &handle))
+            .collect();
+        let balancer = Balancer::new(members, strategy.clone());
+        start_delivery(&configuration, None, balancer, &handle)
+    } else {
+        /**
+         * fan-out: every line is copied to each destination, or to the ones its routes lead to,
+         * and each destination has its own buffer and its own StubbornSink, so that a destination
+         * which is down does not delay the others
+         */
+        let mut deliveries = vec![];
+        let destinations = (0..configuration.connect_to.len())
+            .map(|index| {
+                let stubborn_sink = new_stubborn_sink(&configuration, index, &handle);
+                let delivered = stubborn_sink.delivered();
+                let (buftx, delivery) = start_delivery(&configuration, Some(index), stubborn_sink, &handle);
+                deliveries.push(delivery);
+                Destination::new(configuration.destination_names[index].clone(), buftx, delivered)
+            })
+            .collect();
+        let broadcast = match configuration.routing {
+            None => Broadcast::new(destinations),
+            Some(ref routing) => Broadcast::new(destinations).with_routing(routing.clone()),
+        };
+
+        let reporting = broadcast.report_lag(configuration.lag_report_interval, &handle).unwrap();
+        handle.spawn(reporting.map_err(|_| ()));
+
+        /**
+         * the lines wait here only until each destination buffer takes them, so that with the
+         * `block` policy a full destination buffer pushes back on the clients
+         */
+        let intake = Capacity {
+            lines: Some(INTAKE_BUFFER_LINES),
+            bytes: None,
+        };
+        let (buftx, bufrx) = buffer::bounded(intake, OverflowPolicy::Block, None);
+        let broadcasting = bufrx.forward(broadcast)
+            .map(|_| ())
+            .map_err(|err| error!("Cannot broadcast data: {}", err));
+
+        let delivery: Delivery = Box::new(broadcasting.join(future::join_all(deliveries)).map(|_| ()));
+        (buftx, delivery)
+    };
 
     /**
-     * sends all data received by clients to the remote server
+     * filtered events are dropped as they are sent, so that they never reach the buffer nor the
+     * spool
      */
-    let forwarding = bufrx
-        .map_err(|_| io::Error::new(ErrorKind::Other, "should never happens"))
-        .forward(stubborn_sink);
-    handle.spawn(forwarding.map(|_| ()).map_err(|_| ()));
+    let buftx = if configuration.filter_rules.is_empty() {
+        buftx
+    } else {
+        let filter = Filter::new(configuration.filter_rules.clone());
+        let reporting = filter.report_hits(configuration.filter_report_interval, &handle).unwrap();
+        handle.spawn(reporting.map_err(|_| ()));
+        buftx.filter_with(filter)
+    };
+
+    if configuration.stdin || !configuration.pipes.is_empty() {
+        /**
+         * batch mode: the process ends once every line read has been delivered
+         */
+        let reading = pipe::lines(configuration.stdin, configuration.pipes.clone())
+            .forward(buftx)
+            .map(|_| ())
+            .map_err(|err| error!("Cannot read the input: {}", err));
+
+        match core.run(reading.join(delivery)) {
+            Ok(_) => info!("Every line has been delivered"),
+            Err(()) => std::process::exit(1),
+        }
+        return;
+    }
+
+    handle.spawn(delivery);
 
     /**
      * listening for client connections
      */
-    let server = Server::new(configuration.clone(), handle.clone(), buftx.clone());
+    let server = Server::new(configuration.clone(), handle.clone(), buftx);
     let listening = server.accept_connection();
 
     core.run(listening).unwrap();
 }
 
+fn new_stubborn_sink(configuration: &Conf, index: usize, handle: &Handle) -> StubbornSink {
+    StubbornSink::new(configuration.connect_to[index].clone(),
+                      configuration.clone(),
+                      handle.clone())
+}
+
+/**
+ * Delivery of the data sent to a buffer, it completes once the buffer senders are gone and
+ * everything has been delivered
+ */
+type Delivery = Box<Future<Item = (), Error = ()>>;
+
+/**
+ * Prepares the delivery of the data sent to the returned buffer through `sink`.
+ * A `destination` gives the sink its own buffer and its own spool inside the spool directory,
+ * otherwise they are shared by every destination.
+ */
+fn start_delivery<S>(configuration: &Conf,
+                     destination: Option<usize>,
+                     sink: S,
+                     handle: &Handle)
+                     -> (BufferSender, Delivery)
+    where S: Sink<SinkItem = Event, SinkError = io::Error> + 'static
+{
+    /**
+     * with the spill policy the spool only holds what does not fit in the buffer, otherwise
+     * everything goes through it
+     */
+    let spool = configuration.spool_dir.as_ref().map(|spool_dir| {
+        let spool_dir = match destination {
+            None => PathBuf::from(spool_dir),
+            Some(index) => Path::new(spool_dir).join(&configuration.spool_subdirs[index]),
+        };
+        Spool::open(spool_dir, configuration.spool_segment_size).expect("cannot open the spool directory")
+    });
+    let (overflow_spool, delivery_spool) = match configuration.overflow_policy {
+        OverflowPolicy::Spill => (spool, None),
+        _ => (None, spool),
+    };
+
+    /**
+     * every connected client receives a clone of buftx where to sends data
+     * all received data are read from bufrx and sent the StubbornSink which will try to
+     * sent it to the final destination. If the final destination is unreachable StubbornSink will
+     * returns a NotReady error and received data will remains in this buffer, until it is full
+     */
+    let (buftx, bufrx) = buffer::bounded(configuration.buffer_capacity.clone(),
+                                         configuration.overflow_policy,
+                                         overflow_spool);
+
+    if configuration.overflow_policy == OverflowPolicy::DropNewest ||
+       configuration.overflow_policy == OverflowPolicy::DropOldest {
+        let name = match destination {
+            None => configuration.destination_names.join(","),
+            Some(index) => configuration.destination_names[index].clone(),
+        };
+        let reporting = buftx.report_drops(name, configuration.drop_report_interval, handle).unwrap();
+        handle.spawn(reporting.map_err(|_| ()));
+    }
+
+    /**
+     * events are enriched when they leave the buffer or the spool, right before the sink
+     */
+    let enrichment = configuration.enrichment.clone();
+    let sink = sink.with(move |event| Ok::<Event, io::Error>(enrichment.apply(event)));
+
+    let delivery: Delivery = match delivery_spool {
+        None => {
+            /**
+             * sends all data received by clients to the remote server
+             */
+            let forwarding = bufrx.forward(sink);
+            Box::new(forwarding.map(|_| ()).map_err(|err| error!("Cannot deliver data: {}", err)))
+        }
+        Some(spool) => {
+            /**
+             * all data received by clients is appended to the spool first, and it is read back
+             * from there to be sent to the remote server, so that a restart does not lose it
+             */
+            let (spool_writer, spool_reader) = spool.split();
+
+            let spooling = bufrx.forward(spool_writer)
+                .map(|_| ())
+                .map_err(|err| error!("Cannot write to the spool: {}", err));
+
+            let delivery = spool_reader.deliver(sink).map_err(|err| error!("Cannot read from the spool: {}", err));
+
+            Box::new(spooling.join(delivery).map(|_| ()))
+        }
+    };
+
+    (buftx, delivery)
+}
+
 #[derive(Clone)]
 pub struct Conf {
-    listen_on: String,
-    connect_to: String,
+    listen_on: Option<String>,
+    listen_tls_on: Option<String>,
+    listen_udp_on: Option<String>,
+    udp_max_datagram: usize,
+    listen_unix_on: Option<String>,
+    /**
+     * permissions of the filesystem socket, e.g. 0o660
+     */
+    listen_unix_mode: Option<u32>,
+    syslog: bool,
+    listen_http_on: Option<String>,
+    tail: Option<TailConf>,
+    stdin: bool,
+    /**
+     * named pipes (or any file) read to their end
+     */
+    pipes: Vec<String>,
+    /**
+     * commands whose output is read, restarted whenever they exit
+     */
+    exec: Vec<String>,
+    /**
+     * framing of the stream listeners, newline unless syslog is parsed
+     */
+    input_framing: Option<Framing>,
+    output_framing: Framing,
+    output_format: Format,
+    enrichment: Enrichment,
+    filter_rules: Vec<Rule>,
+    filter_report_interval: Duration,
+    /**
+     * by stream listener name, the empty name being the default of all of them
+     */
+    max_record_size: BTreeMap<String, usize>,
+    oversize_policy: BTreeMap<String, OversizePolicy>,
+    oversize_report_interval: Duration,
+    tls_acceptor: Option<TlsAcceptor>,
+    /**
+     * every destination is a list of addresses in failover order
+     */
+    connect_to: Vec<Vec<String>>,
+    /**
+     * the name given to each destination, its addresses otherwise
+     */
+    destination_names: Vec<String>,
+    routing: Option<Routing>,
+    spool_dir: Option<String>,
+    /**
+     * the spool of each destination when they have one each, named after it
+     */
+    spool_subdirs: Vec<String>,
+    spool_segment_size: u64,
+    ack: bool,
+    /**
+     * lines a destination may have sent and not flushed or acknowledged yet
+     */
+    max_in_flight: usize,
+    connect_timeout: Duration,
+    ack_timeout: Duration,
+    reconnect_backoff: Backoff,
+    buffer_capacity: Capacity,
+    overflow_policy: OverflowPolicy,
+    drop_report_interval: Duration,
+    failover_after: u32,
+    failback_interval: Duration,
+    lag_report_interval: Duration,
+    balance: Option<Strategy>,
+    tls_connector: Option<TlsConnector>,
 }
 
-fn handle_options() -> Option<Conf> {
-    let args: Vec<String> = env::args().collect();
-    let program = args[0].clone();
+impl Conf {
+    /**
+     * The limit of the records received by the stream `listener`, if any
+     */
+    pub fn record_limit(&self, listener: &str) -> Option<RecordLimit> {
+        let max_size = self.max_record_size.get(listener).or_else(|| self.max_record_size.get(""));
+        let policy = self.oversize_policy.get(listener).or_else(|| self.oversize_policy.get(""));
+
+        max_size.map(|max_size| {
+            RecordLimit {
+                max_size: *max_size,
+                policy: policy.cloned().unwrap_or(OversizePolicy::Truncate),
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct TailConf {
+    patterns: Vec<String>,
+    /**
+     * where the position reached in every file is persisted
+     */
+    state_file: PathBuf,
+    interval: Duration,
+}
 
+fn options() -> Options {
     let mut opts = Options::new();
     opts.optopt("l", "listen", "port on where listening", "PORT");
-    opts.optopt("d", "destination", "remote address on where sends data", "ADDRESS:PORT");
+    opts.optopt("", "listen-tls", "port on where listening for TLS connections", "PORT");
+    opts.optopt("", "listen-tls-cert", "PEM certificate chain of the TLS listener", "FILE");
+    opts.optopt("", "listen-tls-key", "PEM private key of the TLS listener", "FILE");
+    opts.optopt("", "listen-tls-client-ca", "PEM bundle of the certificate authorities trusted for client certificates", "FILE");
+    opts.optflag("", "listen-tls-require-client-cert", "refuse TLS clients without a valid certificate");
+    opts.optopt("", "listen-udp", "port on where listening for UDP datagrams, one or more lines each", "PORT");
+    opts.optopt("", "udp-max-datagram", "size in bytes above which datagrams are truncated (default 65507)", "BYTES");
+    opts.optopt("", "listen-unix", "Unix socket on where listening, @NAME for the abstract namespace", "PATH");
+    opts.optopt("", "listen-unix-mode", "permissions of the Unix socket, in octal (e.g. 660)", "MODE");
+    opts.optopt("", "listen-http", "port on where accepting `POST /ingest` requests, one record per line of the body", "PORT");
+    opts.optmulti("", "tail", "file to follow, globs are expanded periodically. Repeat it to follow more files", "PATH");
+    opts.optopt("", "tail-state", "file where the position in the followed files is persisted (default tail.state in the spool directory)", "FILE");
+    opts.optopt("", "tail-interval", "milliseconds between checks of the followed files (default 1000)", "MILLIS");
+    opts.optmulti("", "exec", "run a shell command and deliver the lines of its standard output and error, restarting it when it exits. Repeat it to run more commands", "COMMAND");
+    opts.optflag("", "stdin", "deliver the lines read from the standard input, then exit");
+    opts.optmulti("", "pipe", "deliver the lines read from a named pipe, then exit. Repeat it to read more pipes", "PATH");
+    opts.optopt("", "input-framing", "how clients delimit records: newline, nul, length-prefix or octet-counting (default newline)", "FRAMING");
+    opts.optopt("", "output-framing", "how records are delimited towards the destinations: newline, nul, length-prefix or octet-counting (default newline)", "FRAMING");
+    opts.optopt("", "format", "how events are written to the destinations: raw, json or a template like `{received_at} {peer} {line}`, where any other {NAME} is a field (default raw). {sequence} increases with every event, across restarts as long as the clock does not go back", "FORMAT");
+    opts.optmulti("", "add-field", "set a field of the JSON lines to a template as in --format, such as `host={hostname}`. A dotted PATH is a field of a nested object. Repeat it to set more fields", "PATH=TEMPLATE");
+    opts.optmulti("", "default-field", "as --add-field, only when the field is missing", "PATH=TEMPLATE");
+    opts.optmulti("", "rename-field", "rename a field of the JSON lines", "FROM=TO");
+    opts.optmulti("", "delete-field", "delete a field of the JSON lines", "PATH");
+    opts.optopt("", "non-json", "what to do with lines which are not JSON objects: pass them unchanged or wrap them as {\"message\":LINE} (default pass)", "pass|wrap");
+    opts.optmulti("", "exclude-line", "drop the lines matching a regular expression. Repeat it to drop more lines", "REGEX");
+    opts.optmulti("", "exclude-field", "drop the JSON lines whose field compares to a value with ==, !=, <, <=, >, >= or =~ (a regular expression), such as `@fields.level==100`. When the line has no such field, the event metadata field of that name is compared instead, such as `path` for --tail or `stream` for --exec", "PATH OP VALUE");
+    opts.optmulti("", "include-line", "keep only the lines matching a regular expression, or any other include rule, unless they are excluded", "REGEX");
+    opts.optmulti("", "include-field", "keep only the lines whose JSON or metadata field compares to a value as with --exclude-field, or matching any other include rule", "PATH OP VALUE");
+    opts.optopt("", "filter-report-interval", "milliseconds between reports of how many lines each filter rule matched (default 60000)", "MILLIS");
+    opts.optmulti("", "max-record-size", "maximum size in bytes of a record received by the tcp, tls or unix listener, or by the given one (default unlimited)", "[LISTENER=]BYTES");
+    opts.optmulti("", "oversize-policy", "what to do with larger records: truncate, split or reject, disconnecting the client (default truncate)", "[LISTENER=]POLICY");
+    opts.optopt("", "oversize-report-interval", "milliseconds between reports of how many larger records each client sent (default 60000)", "MILLIS");
+    opts.optflag("", "syslog", "clients send syslog messages (RFC 5424 or RFC 3164, optionally octet counted), parsed into fields");
+    opts.optmulti("d", "destination", "remote addresses on where sends data, in failover order, unix:PATH for a Unix socket, optionally named for --route. Repeat it to send every line to more destinations", "[NAME=]ADDRESS:PORT[,ADDRESS:PORT...]");
+    opts.optmulti("", "route", "send the lines matching a regular expression, or the lines whose JSON or metadata field compares to a value as with --exclude-field, only to the named destination. Repeat it to add routes, they are tried in order", "DESTINATION=line:REGEX|DESTINATION=field:PATH OP VALUE");
+    opts.optopt("", "route-mode", "whether a line takes the first route it matches or all of them (default first)", "first|all");
+    opts.optopt("", "default-route", "destination of the lines matching no route, required with --route", "DESTINATION");
+    opts.optopt("", "spool-dir", "directory where undelivered data is persisted across restarts", "DIR");
+    opts.optopt("", "spool-segment-size", "size in bytes of each spool segment file", "BYTES");
+    opts.optflag("", "ack", "wait for the remote server to acknowledge every line (`ACK <id>`)");
+    opts.optopt("", "ack-timeout", "milliseconds without any acknowledgement, while lines wait for one, before the connection is dropped and the lines are sent again (default 30000)", "MILLIS");
+    opts.optopt("", "max-in-flight", "maximum number of lines sent to a destination and not flushed (or acknowledged, with --ack) yet (default 10000)", "LINES");
+    opts.optopt("", "connect-timeout", "milliseconds before a connection attempt, TLS handshake included, is given up (default 10000)", "MILLIS");
+    opts.optopt("", "reconnect-delay", "milliseconds to wait after the first failed connection attempt (default 100)", "MILLIS");
+    opts.optopt("", "reconnect-multiplier", "factor applied to the delay after every failed attempt (default 2)", "FACTOR");
+    opts.optopt("", "reconnect-max-delay", "maximum milliseconds to wait between connection attempts (default 30000)", "MILLIS");
+    opts.optopt("", "reconnect-jitter", "random fraction of the delay added or removed (default 0.2)", "FRACTION");
+    opts.optopt("", "buffer-lines", "maximum number of lines kept in memory (default unlimited)", "LINES");
+    opts.optopt("", "buffer-bytes", "maximum number of bytes kept in memory (default unlimited)", "BYTES");
+    opts.optopt("", "overflow", "what to do when the buffer is full: block, drop-newest, drop-oldest or spill (default block); with several destinations, block holds every destination back while one buffer is full, the other policies keep them independent", "POLICY");
+    opts.optopt("", "drop-report-interval", "milliseconds between reports of how many lines the overflow policy dropped (default 60000)", "MILLIS");
+    opts.optopt("", "failover-after", "failed connection attempts before moving to the next destination (default 3)", "ATTEMPTS");
+    opts.optopt("", "failback-interval", "milliseconds between checks of the primary destination while failed over (default 10000)", "MILLIS");
+    opts.optopt("", "lag-report-interval", "milliseconds between reports of how many lines each destination is behind (default 60000)", "MILLIS");
+    opts.optopt("", "balance", "spread lines over the destinations instead of sending every line to each of them: round-robin, least-outstanding or hash", "STRATEGY");
+    opts.optopt("", "balance-key", "regex extracting from each line the key to hash (its first capture group if any)", "REGEX");
+    opts.optflag("", "tls", "connect to the destinations over TLS");
+    opts.optopt("", "tls-ca", "PEM bundle of the certificate authorities trusted for the destinations", "FILE");
+    opts.optopt("", "tls-cert", "PEM client certificate chain, for mutual TLS", "FILE");
+    opts.optopt("", "tls-key", "PEM private key of the client certificate", "FILE");
+    opts.optopt("", "tls-sni", "server name sent, and verified with --tls-verify full, instead of the destination host", "NAME");
+    opts.optopt("", "tls-verify", "how the destination certificate is verified: full, no-hostname or none (default full)", "MODE");
     opts.optflag("h", "help", "print this help menu");
+    opts
+}
+
+fn handle_options() -> Option<Conf> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
 
+    let opts = options();
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -93,16 +479,400 @@ fn handle_options() -> Option<Conf> {
         return None;
     }
 
-    let listen_on = matches.opt_str("l");
-    let connect_to = matches.opt_str("d");
+    let connect_to = matches.opt_strs("d");
 
-    if listen_on.is_none() || connect_to.is_none() {
+    let listening = ["l", "listen-tls", "listen-udp", "listen-unix", "listen-http", "tail", "exec"].iter().any(|name| matches.opt_present(name));
+    let batch = matches.opt_present("stdin") || matches.opt_present("pipe");
+    if listening && batch {
+        println!("--stdin and --pipe cannot be combined with listeners\n");
+        print_usage(&program, opts);
+        return None;
+    }
+    if !(listening || batch) || connect_to.is_empty() {
         print_usage(&program, opts);
         return None;
     }
 
-    Some(Conf {
-        listen_on: listen_on.unwrap(),
-        connect_to: connect_to.unwrap(),
+    match build_conf(&matches, connect_to) {
+        Ok(configuration) => Some(configuration),
+        Err(f) => {
+            println!("{}\n", f);
+            print_usage(&program, opts);
+            None
+        }
+    }
+}
+
+fn build_conf(matches: &Matches, connect_to: Vec<String>) -> Result<Conf, String> {
+    let reconnect_backoff = Backoff::new(
+        Duration::from_millis(parse_opt(matches, "reconnect-delay", DEFAULT_RECONNECT_DELAY)?),
+        parse_opt(matches, "reconnect-multiplier", DEFAULT_RECONNECT_MULTIPLIER)?,
+        Duration::from_millis(parse_opt(matches, "reconnect-max-delay", DEFAULT_RECONNECT_MAX_DELAY)?),
+        parse_opt(matches, "reconnect-jitter", DEFAULT_RECONNECT_JITTER)?,
+    );
+
+    let overflow_policy = parse_opt(matches, "overflow", OverflowPolicy::Block)?;
+    if overflow_policy == OverflowPolicy::Spill && !matches.opt_present("spool-dir") {
+        return Err("the spill overflow policy requires --spool-dir".to_string());
+    }
+
+    let max_in_flight = parse_opt(matches, "max-in-flight", DEFAULT_MAX_IN_FLIGHT)?;
+    if max_in_flight == 0 {
+        return Err("--max-in-flight must be at least 1".to_string());
+    }
+
+    let balance = match matches.opt_str("balance") {
+        None => None,
+        Some(strategy) => Some(Strategy::parse(strategy.trim(), matches.opt_str("balance-key"))?),
+    };
+
+    let (destination_names, connect_to) = parse_destinations(&connect_to)?;
+    let routing = match matches.opt_str("default-route") {
+        _ if !matches.opt_present("route") => None,
+        None => return Err("--route requires --default-route".to_string()),
+        Some(default) => {
+            if balance.is_some() {
+                return Err("--route cannot be combined with --balance".to_string());
+            }
+            Some(Routing::parse(&matches.opt_strs("route"),
+                                parse_opt(matches, "route-mode", RouteMode::First)?,
+                                &default,
+                                &destination_names)?)
+        }
+    };
+
+    /**
+     * only fan-out and routing give each destination its own spool
+     */
+    let spool_subdirs = if connect_to.len() == 1 && routing.is_none() || balance.is_some() {
+        vec![]
+    } else {
+        spool_subdirs(&destination_names)?
+    };
+    if let Some(spool_dir) = matches.opt_str("spool-dir") {
+        check_spool_layout(Path::new(&spool_dir), &spool_subdirs)?;
+    }
+
+    let tls_connector = if matches.opt_present("tls") {
+        Some(TlsConnector::new(matches.opt_str("tls-ca"),
+                               matches.opt_str("tls-cert"),
+                               matches.opt_str("tls-key"),
+                               matches.opt_str("tls-sni"),
+                               parse_opt(matches, "tls-verify", Verification::Full)?)?)
+    } else {
+        None
+    };
+
+    let tls_acceptor = match (matches.opt_str("listen-tls"), matches.opt_str("listen-tls-cert"), matches.opt_str("listen-tls-key")) {
+        (None, _, _) => None,
+        (Some(_), Some(cert), Some(key)) => {
+            Some(TlsAcceptor::new(&cert,
+                                  &key,
+                                  matches.opt_str("listen-tls-client-ca"),
+                                  matches.opt_present("listen-tls-require-client-cert"))?)
+        }
+        _ => return Err("--listen-tls requires --listen-tls-cert and --listen-tls-key".to_string()),
+    };
+
+    let tail_patterns = matches.opt_strs("tail");
+    let tail = if tail_patterns.is_empty() {
+        None
+    } else {
+        let state_file = match (matches.opt_str("tail-state"), matches.opt_str("spool-dir")) {
+            (Some(state_file), _) => PathBuf::from(state_file),
+            (None, Some(spool_dir)) => Path::new(&spool_dir).join(TAIL_STATE_FILE),
+            (None, None) => return Err("--tail requires --tail-state or --spool-dir".to_string()),
+        };
+        Some(TailConf {
+            patterns: tail_patterns,
+            state_file: state_file,
+            interval: Duration::from_millis(parse_opt(matches, "tail-interval", DEFAULT_TAIL_INTERVAL)?),
+        })
+    };
+
+    let listen_unix_mode = match matches.opt_str("listen-unix-mode") {
+        None => None,
+        Some(mode) => {
+            Some(u32::from_str_radix(mode.trim(), 8).map_err(|_| format!("invalid value `{}` for --listen-unix-mode", mode))?)
+        }
+    };
+
+    Ok(Conf {
+        listen_on: matches.opt_str("l"),
+        listen_tls_on: matches.opt_str("listen-tls"),
+        listen_udp_on: matches.opt_str("listen-udp"),
+        udp_max_datagram: parse_opt(matches, "udp-max-datagram", udp::MAX_DATAGRAM_SIZE)?,
+        listen_unix_on: matches.opt_str("listen-unix"),
+        listen_unix_mode: listen_unix_mode,
+        syslog: matches.opt_present("syslog"),
+        listen_http_on: matches.opt_str("listen-http"),
+        tail: tail,
+        stdin: matches.opt_present("stdin"),
+        pipes: matches.opt_strs("pipe"),
+        exec: matches.opt_strs("exec"),
+        input_framing: parse_optional(matches, "input-framing")?,
+        output_framing: parse_opt(matches, "output-framing", Framing::Newline)?,
+        output_format: parse_format(matches)?,
+        enrichment: parse_enrichment(matches)?,
+        filter_rules: parse_filter_rules(matches)?,
+        filter_report_interval: Duration::from_millis(parse_opt(matches,
+                                                                "filter-report-interval",
+                                                                DEFAULT_FILTER_REPORT_INTERVAL)?),
+        max_record_size: parse_per_listener(matches, "max-record-size")?,
+        oversize_policy: parse_per_listener(matches, "oversize-policy")?,
+        oversize_report_interval: Duration::from_millis(parse_opt(matches,
+                                                                  "oversize-report-interval",
+                                                                  DEFAULT_OVERSIZE_REPORT_INTERVAL)?),
+        tls_acceptor: tls_acceptor,
+        connect_to: connect_to,
+        destination_names: destination_names,
+        routing: routing,
+        spool_dir: matches.opt_str("spool-dir"),
+        spool_subdirs: spool_subdirs,
+        spool_segment_size: parse_opt(matches, "spool-segment-size", DEFAULT_SPOOL_SEGMENT_SIZE)?,
+        ack: matches.opt_present("ack"),
+        max_in_flight: max_in_flight,
+        connect_timeout: Duration::from_millis(parse_opt(matches, "connect-timeout", DEFAULT_CONNECT_TIMEOUT)?),
+        ack_timeout: Duration::from_millis(parse_opt(matches, "ack-timeout", DEFAULT_ACK_TIMEOUT)?),
+        reconnect_backoff: reconnect_backoff,
+        buffer_capacity: Capacity {
+            lines: parse_optional(matches, "buffer-lines")?,
+            bytes: parse_optional(matches, "buffer-bytes")?,
+        },
+        overflow_policy: overflow_policy,
+        drop_report_interval: Duration::from_millis(parse_opt(matches, "drop-report-interval", DEFAULT_DROP_REPORT_INTERVAL)?),
+        failover_after: parse_opt(matches, "failover-after", DEFAULT_FAILOVER_AFTER)?,
+        failback_interval: Duration::from_millis(parse_opt(matches, "failback-interval", DEFAULT_FAILBACK_INTERVAL)?),
+        lag_report_interval: Duration::from_millis(parse_opt(matches, "lag-report-interval", DEFAULT_LAG_REPORT_INTERVAL)?),
+        balance: balance,
+        tls_connector: tls_connector,
     })
 }
+
+fn parse_opt<T: FromStr>(matches: &Matches, name: &str, default: T) -> Result<T, String> {
+    parse_optional(matches, name).map(|value| value.unwrap_or(default))
+}
+
+/**
+ * Unlike the other options, the template is not trimmed: spaces around it are kept
+ */
+fn parse_format(matches: &Matches) -> Result<Format, String> {
+    match matches.opt_str("format") {
+        None => Ok(Format::Raw),
+        Some(format) => format.parse(),
+    }
+}
+
+/**
+ * The names and the addresses of `[NAME=]ADDRESS[,ADDRESS...]` destinations
+ */
+fn parse_destinations(destinations: &[String]) -> Result<(Vec<String>, Vec<Vec<String>>), String> {
+    let mut names = vec![];
+    let mut addresses = vec![];
+    for destination in destinations {
+        let (name, addrs) = match destination.find('=') {
+            Some(position) => {
+                let name = destination[..position].trim().to_string();
+                if names.contains(&name) {
+                    return Err(format!("destination `{}` is given more than once", name));
+                }
+                (name, &destination[position + 1..])
+            }
+            None => (destination.split(',').map(|addr| addr.trim()).collect::<Vec<_>>().join(","), &destination[..]),
+        };
+        names.push(name);
+        addresses.push(addrs.split(',').map(|addr| addr.trim().to_string()).collect());
+    }
+    Ok((names, addresses))
+}
+
+/**
+ * The destination names made safe as directory names, which must stay distinct
+ */
+fn spool_subdirs(destination_names: &[String]) -> Result<Vec<String>, String> {
+    let mut subdirs: Vec<String> = vec![];
+    for name in destination_names {
+        let subdir: String = name.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect();
+        /**
+         * neither hidden, nor `.` or `..`
+         */
+        let subdir = if subdir.is_empty() || subdir.starts_with('.') {
+            format!("_{}", subdir)
+        } else {
+            subdir
+        };
+        if let Some(other) = subdirs.iter().position(|other| *other == subdir) {
+            return Err(format!("destinations `{}` and `{}` would share the spool directory `{}`, rename one of them",
+                               destination_names[other],
+                               name,
+                               subdir));
+        }
+        subdirs.push(subdir);
+    }
+    Ok(subdirs)
+}
+
+/**
+ * Refuses a spool directory holding undelivered data this configuration would not read: the spool
+ * of a single destination when each destination has its own, or the spool of a destination which
+ * is not configured anymore
+ */
+fn check_spool_layout(spool_dir: &Path, subdirs: &[String]) -> Result<(), String> {
+    let undelivered = |dir: &Path| {
+        spool::undelivered_in(dir).map_err(|err| format!("cannot read the spool in {}: {}", dir.display(), err))
+    };
+
+    if !subdirs.is_empty() && undelivered(spool_dir)? > 0 {
+        return Err(format!("{} holds undelivered data of a single destination, while each destination now has its \
+                            own spool; deliver it with the previous configuration or remove it",
+                           spool_dir.display()));
+    }
+
+    if !spool_dir.is_dir() {
+        return Ok(());
+    }
+    let entries = fs::read_dir(spool_dir).map_err(|err| format!("cannot read {}: {}", spool_dir.display(), err))?;
+    for entry in entries {
+        let path = entry.map_err(|err| format!("cannot read {}: {}", spool_dir.display(), err))?.path();
+        let known = path.file_name().and_then(|name| name.to_str()).map(|name| subdirs.iter().any(|subdir| subdir == name));
+        if path.is_dir() && known != Some(true) && undelivered(&path)? > 0 {
+            return Err(format!("{} holds undelivered data of a destination which is not configured anymore; deliver \
+                                it with the previous configuration or remove it",
+                               path.display()));
+        }
+    }
+    Ok(())
+}
+
+fn parse_enrichment(matches: &Matches) -> Result<Enrichment, String> {
+    Ok(Enrichment {
+        added: matches.opt_strs("add-field").iter().map(|field| enrich::parse_field(field)).collect::<Result<_, _>>()?,
+        defaults: matches.opt_strs("default-field")
+            .iter()
+            .map(|field| enrich::parse_field(field))
+            .collect::<Result<_, _>>()?,
+        renamed: matches.opt_strs("rename-field")
+            .iter()
+            .map(|rename| enrich::parse_rename(rename))
+            .collect::<Result<_, _>>()?,
+        deleted: matches.opt_strs("delete-field").iter().map(|path| path.parse()).collect::<Result<_, _>>()?,
+        non_json: parse_opt(matches, "non-json", NonJson::Pass)?,
+    })
+}
+
+fn parse_filter_rules(matches: &Matches) -> Result<Vec<Rule>, String> {
+    let mut rules = vec![];
+    let kinds: [(&str, Action, fn(&str) -> Result<Matcher, String>); 4] =
+        [("exclude-line", Action::Exclude, Matcher::line),
+         ("exclude-field", Action::Exclude, Matcher::field),
+         ("include-line", Action::Include, Matcher::line),
+         ("include-field", Action::Include, Matcher::field)];
+    for &(name, action, matcher) in &kinds {
+        for rule in matches.opt_strs(name) {
+            rules.push(Rule {
+                action: action,
+                matcher: matcher(&rule)?,
+                description: rule,
+            });
+        }
+    }
+    Ok(rules)
+}
+
+/**
+ * `VALUE` for every stream listener, under the empty name, or `LISTENER=VALUE` for one of them
+ */
+fn parse_per_listener<T: FromStr>(matches: &Matches, name: &str) -> Result<BTreeMap<String, T>, String> {
+    let mut values = BTreeMap::new();
+    for value in matches.opt_strs(name) {
+        let (listener, setting) = match value.find('=') {
+            Some(position) => (value[..position].trim(), &value[position + 1..]),
+            None => ("", &value[..]),
+        };
+        if !listener.is_empty() && !STREAM_LISTENERS.contains(&listener) {
+            return Err(format!("unknown listener `{}` for --{}", listener, name));
+        }
+        let setting = setting.trim().parse().map_err(|_| format!("invalid value `{}` for --{}", value, name))?;
+        values.insert(listener.to_string(), setting);
+    }
+    Ok(values)
+}
+
+fn parse_optional<T: FromStr>(matches: &Matches, name: &str) -> Result<Option<T>, String> {
+    match matches.opt_str(name) {
+        None => Ok(None),
+        Some(value) => value.trim().parse().map(Some).map_err(|_| format!("invalid value `{}` for --{}", value, name)),
+    }
+}
+
+/**
+ * The configuration given by the command line `args`
+ */
+#[cfg(test)]
+pub fn test_conf(args: &[&str]) -> Conf {
+    let matches = options().parse(args).unwrap();
+    build_conf(&matches, matches.opt_strs("d")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stubborn-sink-layout-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn spool_lines(dir: &Path, count: usize) {
+        let (mut writer, _reader) = Spool::open(dir, 1024).unwrap().split();
+        for line in 0..count {
+            writer.start_send(Event::new(line.to_string())).unwrap();
+        }
+        writer.poll_complete().unwrap();
+    }
+
+    fn subdirs(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn a_missing_spool_directory_is_accepted() {
+        let dir = spool_dir("missing");
+        assert!(check_spool_layout(&dir, &[]).is_ok());
+        assert!(check_spool_layout(&dir, &subdirs(&["a", "b"])).is_ok());
+    }
+
+    #[test]
+    fn the_spool_of_a_single_destination_is_refused_with_several() {
+        let dir = spool_dir("single");
+        spool_lines(&dir, 2);
+        assert!(check_spool_layout(&dir, &[]).is_ok());
+        assert!(check_spool_layout(&dir, &subdirs(&["a", "b"])).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_spools_of_removed_destinations_with_data_are_refused() {
+        let dir = spool_dir("removed");
+        spool_lines(&dir.join("a"), 1);
+        spool_lines(&dir.join("old"), 0);
+        assert!(check_spool_layout(&dir, &subdirs(&["a", "b"])).is_ok());
+
+        spool_lines(&dir.join("gone"), 1);
+        let refused = check_spool_layout(&dir, &subdirs(&["a", "b"])).unwrap_err();
+        assert!(refused.contains("gone"), "{}", refused);
+        assert!(check_spool_layout(&dir, &subdirs(&["a", "gone"])).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn destinations_get_distinct_spool_directories() {
+        let names = subdirs(&["primary", "a/b", ".hidden", ""]);
+        assert_eq!(spool_subdirs(&names).unwrap(), ["primary", "a_b", "_.hidden", "_"]);
+        assert!(spool_subdirs(&subdirs(&["a/b", "a:b"])).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use event::Event;
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc::{self, Sender};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind};
+use std::thread;
+
+/**
+ * lines read ahead of the buffer by every input thread
+ */
+const READ_AHEAD: usize = 1024;
+
+/**
+ * The lines read from the standard input and from files, usually named pipes, until all of them
+ * reach their end.
+ *
+ * Reads block, so every input is read on its own thread; the threads stop reading while the
+ * buffer does not accept more lines.
+ */
+pub fn lines(stdin: bool, paths: Vec<String>) -> Box<Stream<Item = Event, Error = io::Error>> {
+    let (sender, receiver) = mpsc::channel(READ_AHEAD);
+
+    if stdin {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let reading = read(stdin.lock(),
+                               |line| Event::new(line).received_on("stdin", None),
+                               sender.clone());
+            if let Err(err) = reading {
+                let _ = sender.send(Err(err)).wait();
+            }
+        });
+    }
+
+    for path in paths {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            /**
+             * opening a named pipe blocks until there is a writer
+             */
+            let reading = File::open(&path).and_then(|file| {
+                read(BufReader::new(file),
+                     |line| Event::new(line).received_on("pipe", None).with_field("path", path.clone()),
+                     sender.clone())
+            });
+            if let Err(err) = reading {
+                let _ = sender.send(Err(io::Error::new(err.kind(), format!("{}: {}", path, err)))).wait();
+            }
+        });
+    }
+
+    Box::new(receiver.map_err(|_| io::Error::new(ErrorKind::Other, "input thread died"))
+        .and_then(|event| event))
+}
+
+/**
+ * Sends every line of `reader`, turned into an event by `to_event`, until its end.
+ * Returns false when the receiver is gone.
+ */
+pub fn read<R, F>(mut reader: R, to_event: F, mut sender: Sender<io::Result<Event>>) -> io::Result<bool>
+    where R: BufRead,
+          F: Fn(String) -> Event
+{
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(true);
+        }
+
+        let event = to_event(String::from_utf8_lossy(&line).trim_end_matches(&['\r', '\n'][..]).to_string());
+        sender = match sender.send(Ok(event)).wait() {
+            Ok(sender) => sender,
+            Err(_) => return Ok(false),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use std::process;
+
+    fn read_all(input: &[u8]) -> Vec<String> {
+        let (sender, receiver) = mpsc::channel(READ_AHEAD);
+        let input = input.to_vec();
+        let reading = thread::spawn(move || read(Cursor::new(input), Event::new, sender).unwrap());
+        let lines = receiver.map(|event| event.unwrap().line).collect().wait().unwrap();
+        assert!(reading.join().unwrap());
+        lines
+    }
+
+    #[test]
+    fn every_line_is_read_until_the_end() {
+        assert_eq!(read_all(b"one\r\ntwo\n\nlast without newline"),
+                   ["one", "two", "", "last without newline"]);
+        assert_eq!(read_all(b"caf\xe9\n"), ["caf\u{fffd}"]);
+        assert!(read_all(b"").is_empty());
+    }
+
+    #[test]
+    fn reading_stops_once_the_lines_are_not_wanted() {
+        let (sender, receiver) = mpsc::channel(READ_AHEAD);
+        drop(receiver);
+        assert!(!read(Cursor::new(b"one\ntwo\n".to_vec()), Event::new, sender).unwrap());
+    }
+
+    #[test]
+    fn the_lines_of_every_file_are_read_with_their_path() {
+        let path = env::temp_dir().join(format!("stubborn-sink-pipe-{}", process::id()));
+        fs::File::create(&path).unwrap().write_all(b"one\ntwo\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let events = lines(false, vec![path.clone()]).collect().wait().unwrap();
+        assert_eq!(events.iter().map(|event| &event.line[..]).collect::<Vec<_>>(), ["one", "two"]);
+        assert!(events.iter().all(|event| event.fields.get("path") == Some(&path)));
+        assert!(events.iter().all(|event| event.listener == Some("pipe".to_string())));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_is_an_error() {
+        let path = env::temp_dir().join(format!("stubborn-sink-pipe-{}-missing", process::id()));
+        let err = lines(false, vec![path.to_string_lossy().into_owned()]).collect().wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
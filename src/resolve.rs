@@ -0,0 +1,161 @@
+use futures::future::Future;
+use futures::sync::oneshot;
+use futures::{Async, Poll};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use tokio_core::net::{TcpStream, TcpStreamNew};
+use tokio_core::reactor::Handle;
+
+/**
+ * Resolves `address` (`HOST:PORT`) to all its A/AAAA records.
+ *
+ * The system resolver blocks, so it runs on its own thread to keep the event loop going.
+ */
+pub fn resolve(address: &str) -> Box<Future<Item = Vec<SocketAddr>, Error = io::Error>> {
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        return Box::new(::futures::future::ok(vec![addr]));
+    }
+
+    let (tx, rx) = oneshot::channel();
+    let host = address.to_string();
+    thread::spawn(move || {
+        let resolved = host.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>());
+        let _ = tx.send(resolved);
+    });
+
+    let address = address.to_string();
+    Box::new(rx.map_err(|_| io::Error::new(ErrorKind::Other, "resolver thread died"))
+        .and_then(move |resolved| {
+            let addrs = resolved?;
+            if addrs.is_empty() {
+                return Err(io::Error::new(ErrorKind::NotFound, format!("{} has no address", address)));
+            }
+            debug!("{} resolved to {:?}", address, addrs);
+            Ok(addrs)
+        }))
+}
+
+/**
+ * `HOST:PORT` or `[IPv6]:PORT` to `HOST` or `IPv6`, other addresses are left as they are
+ */
+pub fn host(address: &str) -> &str {
+    match address.rfind(':') {
+        Some(position) if address[position + 1..].parse::<u16>().is_ok() => {
+            address[..position].trim_start_matches('[').trim_end_matches(']')
+        }
+        _ => address,
+    }
+}
+
+/**
+ * Resolves `address` and connects to the first of its records accepting the connection
+ */
+pub fn connect(address: &str, handle: &Handle) -> Box<Future<Item = TcpStream, Error = io::Error>> {
+    let handle = handle.clone();
+    Box::new(resolve(address).and_then(move |addrs| {
+        ConnectAny {
+            addrs: addrs.into_iter().collect(),
+            current: None,
+            handle: handle,
+        }
+    }))
+}
+
+struct ConnectAny {
+    addrs: VecDeque<SocketAddr>,
+    current: Option<TcpStreamNew>,
+    handle: Handle,
+}
+
+impl Future for ConnectAny {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        loop {
+            if let Some(ref mut current) = self.current {
+                match current.poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(stream)),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => {
+                        if self.addrs.is_empty() {
+                            return Err(err);
+                        }
+                        debug!("Connection failed: {}, trying the next address", err);
+                    }
+                }
+            }
+
+            let addr = self.addrs.pop_front().expect("at least one address to connect to");
+            self.current = Some(TcpStream::connect(&addr, &self.handle));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    /**
+     * An address nothing listens on anymore
+     */
+    fn refused() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn connect_any(addrs: Vec<SocketAddr>, core: &mut Core) -> io::Result<TcpStream> {
+        let connecting = ConnectAny {
+            addrs: addrs.into_iter().collect(),
+            current: None,
+            handle: core.handle(),
+        };
+        core.run(connecting)
+    }
+
+    #[test]
+    fn ip_addresses_are_not_looked_up() {
+        assert_eq!(resolve("127.0.0.1:5140").wait().unwrap(), ["127.0.0.1:5140".parse().unwrap()]);
+        assert_eq!(resolve("[::1]:5140").wait().unwrap(), ["[::1]:5140".parse().unwrap()]);
+    }
+
+    #[test]
+    fn host_names_resolve_to_every_record() {
+        let addrs = resolve("localhost:5140").wait().unwrap();
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback() && addr.port() == 5140), "{:?}", addrs);
+    }
+
+    #[test]
+    fn the_next_record_is_tried_when_one_refuses_the_connection() {
+        let mut core = Core::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listening = listener.local_addr().unwrap();
+
+        let stream = connect_any(vec![refused(), refused(), listening], &mut core).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening);
+    }
+
+    #[test]
+    fn the_connection_fails_when_every_record_refuses_it() {
+        let mut core = Core::new().unwrap();
+        let err = connect_any(vec![refused(), refused()], &mut core).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn the_port_is_dropped_from_the_host() {
+        assert_eq!(host("example.com:443"), "example.com");
+        assert_eq!(host("127.0.0.1:5140"), "127.0.0.1");
+        assert_eq!(host("[::1]:5140"), "::1");
+    }
+
+    #[test]
+    fn addresses_without_a_port_are_left_as_they_are() {
+        assert_eq!(host("example.com"), "example.com");
+        assert_eq!(host("unix:/run/sink.sock"), "unix:/run/sink.sock");
+    }
+}
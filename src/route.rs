@@ -0,0 +1,199 @@
+use event::Event;
+use filter::Matcher;
+use serde_json;
+use std::str::FromStr;
+
+/**
+ * Which routes an event takes when it matches more than one
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteMode {
+    /**
+     * only the first matching route, in the order the routes are given
+     */
+    First,
+    /**
+     * every matching route, an event going at most once to each destination
+     */
+    All,
+}
+
+impl FromStr for RouteMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode {
+            "first" => Ok(RouteMode::First),
+            "all" => Ok(RouteMode::All),
+            other => Err(format!("unknown route mode `{}`", other)),
+        }
+    }
+}
+
+/**
+ * Sends the events matching `matcher` to the destination at `destination`
+ */
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub destination: usize,
+    pub matcher: Matcher,
+}
+
+/**
+ * Chooses the destinations of every event; events matching no route go to the default
+ * destination
+ */
+#[derive(Clone, Debug)]
+pub struct Routing {
+    routes: Vec<Route>,
+    mode: RouteMode,
+    default: usize,
+    destinations: usize,
+    /**
+     * the line is parsed only when a route looks at its fields
+     */
+    parse_json: bool,
+}
+
+impl Routing {
+    /**
+     * `routes` are `DESTINATION=line:REGEX` or `DESTINATION=field:PATH OP VALUE`, as given to
+     * `--route`, and destinations are referred to by their name in `names`
+     */
+    pub fn parse(routes: &[String], mode: RouteMode, default: &str, names: &[String]) -> Result<Self, String> {
+        let index = |name: &str| {
+            names.iter()
+                .position(|known| known == name.trim())
+                .ok_or_else(|| format!("unknown destination `{}`", name.trim()))
+        };
+
+        let routes = routes.iter()
+            .map(|route| {
+                let position = route.find('=').ok_or_else(|| format!("`{}` is not DESTINATION=MATCH", route))?;
+                let destination = index(&route[..position])?;
+                let rule = &route[position + 1..];
+                let matcher = if rule.starts_with("line:") {
+                    Matcher::line(&rule["line:".len()..])?
+                } else if rule.starts_with("field:") {
+                    Matcher::field(&rule["field:".len()..])?
+                } else {
+                    return Err(format!("`{}` is neither line:REGEX nor field:PATH OP VALUE", rule));
+                };
+                Ok(Route {
+                    destination: destination,
+                    matcher: matcher,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let parse_json = routes.iter().any(|route| match route.matcher {
+            Matcher::Field(..) => true,
+            Matcher::Line(_) => false,
+        });
+
+        Ok(Routing {
+            routes: routes,
+            mode: mode,
+            default: index(default)?,
+            destinations: names.len(),
+            parse_json: parse_json,
+        })
+    }
+
+    /**
+     * Whether `event` goes to each destination, by index
+     */
+    pub fn select(&self, event: &Event) -> Vec<bool> {
+        let json = if self.parse_json {
+            serde_json::from_str(&event.line).ok()
+        } else {
+            None
+        };
+
+        let mut selected = vec![false; self.destinations];
+        let mut matched = false;
+        for route in &self.routes {
+            if route.matcher.matches(event, json.as_ref()) {
+                selected[route.destination] = true;
+                matched = true;
+                if self.mode == RouteMode::First {
+                    break;
+                }
+            }
+        }
+        if !matched {
+            selected[self.default] = true;
+        }
+
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routing(routes: &[&str], mode: RouteMode) -> Result<Routing, String> {
+        let routes = routes.iter().map(|route| route.to_string()).collect::<Vec<_>>();
+        let names = ["archive", "alerts", "metrics"].iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        Routing::parse(&routes, mode, "archive", &names)
+    }
+
+    fn select(routing: &Routing, line: &str) -> Vec<bool> {
+        routing.select(&Event::new(line.to_string()))
+    }
+
+    #[test]
+    fn routes_name_a_destination_and_a_matcher() {
+        let parsed = routing(&[" alerts =line:ERROR", "metrics=field:kind==metric"], RouteMode::First).unwrap();
+        assert_eq!(parsed.routes.iter().map(|route| route.destination).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!((parsed.default, parsed.destinations), (0, 3));
+        assert!(parsed.parse_json);
+
+        assert!(!routing(&["alerts=line:ERROR"], RouteMode::First).unwrap().parse_json);
+    }
+
+    #[test]
+    fn invalid_routes_are_refused() {
+        assert!(routing(&["alerts"], RouteMode::First).is_err());
+        assert!(routing(&["unknown=line:x"], RouteMode::First).is_err());
+        assert!(routing(&["alerts=regex:x"], RouteMode::First).is_err());
+        assert!(routing(&["alerts=line:("], RouteMode::First).is_err());
+        assert!(routing(&["alerts=field:kind"], RouteMode::First).is_err());
+
+        let names = vec!["archive".to_string()];
+        assert!(Routing::parse(&[], RouteMode::First, "unknown", &names).is_err());
+        assert!("some".parse::<RouteMode>().is_err());
+    }
+
+    #[test]
+    fn events_matching_no_route_go_to_the_default_destination() {
+        let routing = routing(&["alerts=line:ERROR"], RouteMode::First).unwrap();
+        assert_eq!(select(&routing, "INFO started"), [true, false, false]);
+        assert_eq!(select(&routing, "ERROR failed"), [false, true, false]);
+    }
+
+    #[test]
+    fn only_the_first_matching_route_is_taken() {
+        let routing = routing(&["alerts=line:ERROR", "metrics=line:disk", "archive=line:ERROR"], RouteMode::First)
+            .unwrap();
+        assert_eq!(select(&routing, "ERROR disk full"), [false, true, false]);
+        assert_eq!(select(&routing, "disk usage"), [false, false, true]);
+    }
+
+    #[test]
+    fn every_matching_route_is_taken_once() {
+        let routing = routing(&["alerts=line:ERROR", "metrics=line:disk", "alerts=line:full"], RouteMode::All)
+            .unwrap();
+        assert_eq!(select(&routing, "ERROR disk full"), [false, true, true]);
+        assert_eq!(select(&routing, "nothing"), [true, false, false]);
+    }
+
+    #[test]
+    fn field_routes_look_at_json_lines() {
+        let routing = routing(&["metrics=field:kind==metric"], RouteMode::First).unwrap();
+        assert_eq!(select(&routing, r#"{"kind":"metric"}"#), [false, false, true]);
+        assert_eq!(select(&routing, r#"{"kind":"log"}"#), [true, false, false]);
+        assert_eq!(select(&routing, "kind==metric"), [true, false, false]);
+    }
+}
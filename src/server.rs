@@ -1,49 +1,207 @@
-use futures::future::Future;
-use futures::sync::mpsc::UnboundedSender;
+use buffer::{BufferSender, OverflowPolicy};
+use codec::{Framing, OversizeCounts, RecordCodec, RecordLimit};
+use event::Event;
+use exec;
+use http;
+use futures::future::{self, Future};
 use futures::{Stream, Sink};
-use std::io::{self, ErrorKind};
+use std::collections::BTreeMap;
 use std::time::Duration;
 use std;
-use super::Conf;
+use super::{Conf, TailConf};
+use tls::TlsAcceptor;
 use tokio_core::io::Io;
-use tokio_core::net::TcpListener;
+use tokio_core::net::{TcpListener, UdpSocket};
 use tokio_core::reactor::Handle;
-use tokio_line::LineCodec;
 use tokio_timer::*;
+use syslog::{self, SyslogCodec};
+use tail::Tail;
+use udp::Datagrams;
+use unix;
 
 pub struct Server {
     configuration: Conf,
     handle: Handle,
-    buftx: UnboundedSender<String>,
+    buftx: BufferSender,
+    oversize_counts: OversizeCounts,
+}
+
+/**
+ * How the connections of a stream listener are read
+ */
+#[derive(Clone)]
+struct Reading {
+    listener: &'static str,
+    framing: Option<Framing>,
+    parse_syslog: bool,
+    limit: Option<RecordLimit>,
+    oversize_counts: OversizeCounts,
 }
 
 impl Server {
-    pub fn new(configuration: Conf, handle: Handle, buftx: UnboundedSender<String>) -> Self {
+    pub fn new(configuration: Conf, handle: Handle, buftx: BufferSender) -> Self {
         Server {
             configuration: configuration,
             handle: handle,
             buftx: buftx,
+            oversize_counts: OversizeCounts::default(),
+        }
+    }
+
+    fn reading(&self, listener: &'static str) -> Reading {
+        Reading {
+            listener: listener,
+            framing: self.configuration.input_framing,
+            parse_syslog: self.configuration.syslog,
+            limit: self.configuration.record_limit(listener),
+            oversize_counts: self.oversize_counts.clone(),
         }
     }
 
+    /**
+     * Plaintext, TLS, UDP, Unix socket and HTTP listeners run side by side with the tailed files
+     * and the commands, sending to the same buffer
+     */
     #[cfg(not(any(fake_clients)))]
     pub fn accept_connection(self) -> Box<Future<Item = (), Error = std::io::Error>> {
-        let address = self.configuration.listen_on.parse().unwrap();
+        let mut listeners = vec![];
+
+        if let Some(ref address) = self.configuration.listen_on {
+            listeners.push(self.listen(address));
+        }
+        if let Some(ref address) = self.configuration.listen_tls_on {
+            let acceptor = self.configuration.tls_acceptor.clone().expect("a TLS listener requires a certificate");
+            listeners.push(self.listen_tls(address, acceptor));
+        }
+        if let Some(ref address) = self.configuration.listen_udp_on {
+            listeners.push(self.listen_udp(address));
+        }
+        if let Some(ref path) = self.configuration.listen_unix_on {
+            listeners.push(self.listen_unix(path));
+        }
+        if let Some(ref address) = self.configuration.listen_http_on {
+            listeners.push(self.listen_http(address));
+        }
+        if let Some(ref tail) = self.configuration.tail {
+            listeners.push(self.tail(tail));
+        }
+        for command in &self.configuration.exec {
+            let running = exec::lines(command.clone(), self.configuration.reconnect_backoff.clone())
+                .forward(self.buftx.clone())
+                .map(|_| ());
+            listeners.push(Box::new(running));
+        }
+
+        if !self.configuration.max_record_size.is_empty() {
+            let reporting = self.oversize_counts.report(self.configuration.oversize_report_interval, &self.handle).unwrap();
+            self.handle.spawn(reporting.map_err(|_| ()));
+        }
+
+        Box::new(future::join_all(listeners).map(|_| ()))
+    }
+
+    fn listen(&self, address: &str) -> Box<Future<Item = (), Error = std::io::Error>> {
+        let address = address.parse().unwrap();
+        let listener = TcpListener::bind(&address, &self.handle).unwrap();
+        let connections = listener.incoming();
+
+        let handle = self.handle.clone();
+        let buftx = self.buftx.clone();
+        let reading = self.reading("tcp");
+        let server = connections.for_each(move |(socket, peer)| {
+            process_connection(socket, peer.to_string(), BTreeMap::new(), &reading, &buftx, &handle);
+
+            Ok(())
+        });
+
+        Box::new(server)
+    }
+
+    /**
+     * The subject of the client certificate, if any, is attached to every line of the connection
+     */
+    fn listen_tls(&self, address: &str, acceptor: TlsAcceptor) -> Box<Future<Item = (), Error = std::io::Error>> {
+        let address = address.parse().unwrap();
         let listener = TcpListener::bind(&address, &self.handle).unwrap();
         let connections = listener.incoming();
 
-        let server = connections.for_each(move |(socket, _)| {
-            let transport = socket.framed(LineCodec);
+        let handle = self.handle.clone();
+        let buftx = self.buftx.clone();
+        let reading = self.reading("tls");
+        let server = connections.for_each(move |(socket, peer)| {
+            let buftx = buftx.clone();
+            let reading = reading.clone();
+            let connection_handle = handle.clone();
+            let handshake = acceptor.accept(socket)
+                .map(move |(socket, subject)| {
+                    let mut fields = BTreeMap::new();
+                    if let Some(subject) = subject {
+                        debug!("Client {} authenticated as {}", peer, subject);
+                        fields.insert("tls_subject".to_string(), subject);
+                    }
+                    process_connection(socket, peer.to_string(), fields, &reading, &buftx, &connection_handle);
+                })
+                .map_err(move |err| warn!("Client {}: {}", peer, err));
+
+            handle.spawn(handshake);
+
+            Ok(())
+        });
+
+        Box::new(server)
+    }
+
+    /**
+     * UDP has no backpressure: when the buffer blocks, datagrams queue up in the socket receive
+     * buffer and the kernel drops them once it is full
+     */
+    fn listen_udp(&self, address: &str) -> Box<Future<Item = (), Error = std::io::Error>> {
+        let address = address.parse().unwrap();
+        let socket = UdpSocket::bind(&address, &self.handle).unwrap();
+
+        /**
+         * every datagram is a single syslog message (RFC 5426), no framing is involved
+         */
+        let parse_syslog = self.configuration.syslog;
+        let receiving = Datagrams::new(socket, self.configuration.udp_max_datagram)
+            .map(move |event| if parse_syslog { syslog::parse(event) } else { event })
+            .forward(self.buftx.clone())
+            .map(|_| ());
+
+        Box::new(receiving)
+    }
+
+    fn listen_unix(&self, path: &str) -> Box<Future<Item = (), Error = std::io::Error>> {
+        let path = unix::socket_path(path);
+        let listener = unix::bind(&path, self.configuration.listen_unix_mode, &self.handle).unwrap();
+        let connections = listener.incoming();
+
+        let handle = self.handle.clone();
+        let buftx = self.buftx.clone();
+        let reading = self.reading("unix");
+        let server = connections.for_each(move |(socket, peer)| {
+            process_connection(socket, format!("{:?}", peer), BTreeMap::new(), &reading, &buftx, &handle);
 
-            let buftx = self.buftx.clone();
-            let process_connection = transport.for_each(move |line| {
-                buftx.clone().send(line)
-                    .map_err(|err| io::Error::new(ErrorKind::Other, err))
-                    .map(|_| ())
-            })
-            .map_err(|_| ());
+            Ok(())
+        });
 
-            self.handle.spawn(process_connection);
+        Box::new(server)
+    }
+
+    fn listen_http(&self, address: &str) -> Box<Future<Item = (), Error = std::io::Error>> {
+        let address = address.parse().unwrap();
+        let listener = TcpListener::bind(&address, &self.handle).unwrap();
+        let connections = listener.incoming();
+
+        let handle = self.handle.clone();
+        let buftx = self.buftx.clone();
+        /**
+         * the records are persisted once in the spool, unless it only takes the overflow
+         */
+        let persisted = self.configuration.spool_dir.is_some() &&
+                        self.configuration.overflow_policy != OverflowPolicy::Spill;
+        let server = connections.for_each(move |(socket, peer)| {
+            http::process_connection(socket, peer.to_string(), &buftx, persisted, &handle);
 
             Ok(())
         });
@@ -51,6 +209,16 @@ impl Server {
         Box::new(server)
     }
 
+    fn tail(&self, configuration: &TailConf) -> Box<Future<Item = (), Error = std::io::Error>> {
+        let tail = Tail::new(configuration.patterns.clone(),
+                             configuration.state_file.clone(),
+                             configuration.interval,
+                             &self.handle)
+            .expect("cannot read the tail state file");
+
+        Box::new(tail.forward(self.buftx.clone()).map(|_| ()))
+    }
+
     #[cfg(fake_clients)]
     pub fn accept_connection(self) -> Box<Future<Item = (), Error = TimerError>> {
         let timer = Timer::default();
@@ -60,7 +228,7 @@ impl Server {
             i = i + 1;
             debug!("Interval {}", i);
             self.buftx.clone()
-                .send(format!(r#"{{"@timestamp":"2017-03-24T09:16:42.636040+01:00","@source":"dev-all-onebiptrusty cli","@fields":{{"channel":"integrationtest-client","level":100,"extra_level_name":"DEBUG","extra_uname":"dev-all-onebiptrusty","extra_sapi":"cli","extra_process_id":17954}},"@message":"Message {}"}}"#, i).to_string())
+                .send(Event::from(format!(r#"{{"@timestamp":"2017-03-24T09:16:42.636040+01:00","@source":"dev-all-onebiptrusty cli","@fields":{{"channel":"integrationtest-client","level":100,"extra_level_name":"DEBUG","extra_uname":"dev-all-onebiptrusty","extra_sapi":"cli","extra_process_id":17954}},"@message":"Message {}"}}"#, i).to_string()))
                 .map(|_| ())
                 .map_err(|_| TimerError::NoCapacity)
         });
@@ -75,3 +243,43 @@ impl Server {
         Box::new(background_tasks)
     }
 }
+
+/**
+ * With `parse_syslog` the messages are framed as in RFC 6587, unless another framing is chosen,
+ * and parsed into fields
+ */
+fn process_connection<S: Io + 'static>(socket: S,
+                                       peer: String,
+                                       fields: BTreeMap<String, String>,
+                                       reading: &Reading,
+                                       buftx: &BufferSender,
+                                       handle: &Handle) {
+    let parse_syslog = reading.parse_syslog;
+    let listener = reading.listener;
+    let oversize_counts = reading.oversize_counts.clone();
+    let transport: Box<Stream<Item = String, Error = std::io::Error>> = match reading.framing {
+        None if reading.parse_syslog => Box::new(socket.framed(SyslogCodec::new(reading.limit, peer.clone(), oversize_counts))),
+        framing => {
+            let codec = RecordCodec::new(framing.unwrap_or(Framing::Newline)).with_limit(reading.limit, peer.clone(), oversize_counts);
+            Box::new(socket.framed(codec))
+        }
+    };
+
+    /**
+     * when the buffer is full and the overflow policy is to block, the buffer stops
+     * accepting lines and so the client transport is not polled anymore
+     */
+    let client = peer.clone();
+    let process_connection = transport
+        .map(move |line| {
+            let mut event = Event::new(line).received_on(listener, Some(client.clone()));
+            event.fields.extend(fields.clone());
+            event
+        })
+        .map(move |event| if parse_syslog { syslog::parse(event) } else { event })
+        .forward(buftx.clone())
+        .map(|_| ())
+        .map_err(move |err| warn!("Client {}: {}", peer, err));
+
+    handle.spawn(process_connection);
+}
@@ -0,0 +1,586 @@
+use futures::future::Future;
+use futures::task::{self, Task};
+use event::{Event, Receipt};
+use futures::{Async, AsyncSink, Poll, StartSend, Stream, Sink};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const SEGMENT_EXTENSION: &'static str = "seg";
+const CURSOR_FILE: &'static str = "cursor";
+
+/**
+ * Append-only on-disk spool.
+ *
+ * Accepted lines are appended to numbered segment files inside `dir`. A cursor file records the
+ * position of the last line confirmed by the destination, so that on startup only undelivered
+ * lines are replayed. A segment is removed as soon as the cursor moves past it.
+ */
+#[derive(Clone)]
+pub struct Spool {
+    inner: Rc<RefCell<Inner>>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    segment_size: u64,
+    segments: VecDeque<u64>,
+    writing: WritingSegment,
+    reading: Option<ReadingSegment>,
+    committed: (u64, u64),
+    /**
+     * records left undelivered by the previous run
+     */
+    undelivered: usize,
+    blocked_reader: Option<Task>,
+    /**
+     * set once the writer is dropped, the reader ends after the last line
+     */
+    closed: bool,
+}
+
+struct WritingSegment {
+    id: u64,
+    file: File,
+    size: u64,
+}
+
+struct ReadingSegment {
+    id: u64,
+    reader: BufReader<File>,
+    offset: u64,
+}
+
+pub struct SpoolWriter {
+    spool: Spool,
+    /**
+     * completed once the lines they wait for are synced to the disk
+     */
+    unsynced: Vec<Receipt>,
+}
+
+pub struct SpoolReader {
+    spool: Spool,
+}
+
+pub struct Delivery<S> {
+    reader: SpoolReader,
+    sink: S,
+    buffered: Option<Event>,
+}
+
+impl Spool {
+    pub fn open<P: AsRef<Path>>(dir: P, segment_size: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let segments = list_segments(&dir)?;
+        let committed = read_cursor(&dir)?.unwrap_or((segments.first().cloned().unwrap_or(0), 0));
+
+        /**
+         * everything before the cursor has already been delivered before the last shutdown
+         */
+        let mut segments: VecDeque<u64> = segments.into_iter()
+            .filter(|id| {
+                if *id < committed.0 {
+                    let _ = fs::remove_file(segment_path(&dir, *id));
+                    return false;
+                }
+                true
+            })
+            .collect();
+
+        let undelivered = count_undelivered(&dir, segments.iter(), committed)?;
+        if !segments.is_empty() {
+            info!("Spool in {} contains {} undelivered record(s) in {} segment(s)",
+                  dir.display(),
+                  undelivered,
+                  segments.len());
+        }
+
+        /**
+         * a new segment is always started, a previous run may have left a truncated line at the end
+         * of the last one
+         */
+        let writing_id = segments.back().map(|id| id + 1).unwrap_or(0).max(committed.0 + 1);
+        let writing = WritingSegment::create(&dir, writing_id)?;
+        segments.push_back(writing_id);
+
+        Ok(Spool {
+            inner: Rc::new(RefCell::new(Inner {
+                dir: dir,
+                segment_size: segment_size,
+                segments: segments,
+                writing: writing,
+                reading: None,
+                committed: committed,
+                undelivered: undelivered,
+                blocked_reader: None,
+                closed: false,
+            })),
+        })
+    }
+
+    /**
+     * The records left undelivered by the previous run, found when the spool was opened
+     */
+    pub fn undelivered(&self) -> usize {
+        self.inner.borrow().undelivered
+    }
+
+    pub fn split(self) -> (SpoolWriter, SpoolReader) {
+        let writer = SpoolWriter {
+            spool: self.clone(),
+            unsynced: vec![],
+        };
+        (writer, SpoolReader { spool: self })
+    }
+}
+
+impl Inner {
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        if self.writing.size >= self.segment_size {
+            /**
+             * the receipts are completed by syncing the segment being written, those waiting for
+             * lines of this one could not be otherwise
+             */
+            self.writing.file.sync_data()?;
+            let id = self.writing.id + 1;
+            self.writing = WritingSegment::create(&self.dir, id)?;
+            self.segments.push_back(id);
+            debug!("Spool rolled to segment {}", id);
+        }
+
+        let mut record = String::with_capacity(line.len() + 1);
+        record.push_str(line);
+        record.push('\n');
+        self.writing.file.write_all(record.as_bytes())?;
+        self.writing.size += record.len() as u64;
+
+        if let Some(task) = self.blocked_reader.take() {
+            task.notify();
+        }
+
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if self.reading.is_none() {
+                let id = match self.segments.iter().find(|id| **id >= self.committed.0) {
+                    Some(id) => *id,
+                    None => return Ok(None),
+                };
+                let offset = if id == self.committed.0 { self.committed.1 } else { 0 };
+                self.reading = Some(ReadingSegment::open(&self.dir, id, offset)?);
+            }
+
+            let is_writing = self.reading.as_ref().map(|r| r.id) == Some(self.writing.id);
+            let finished = {
+                let reading = self.reading.as_mut().unwrap();
+                if let Some(line) = reading.read_line(!is_writing)? {
+                    return Ok(Some(line));
+                }
+                if is_writing {
+                    return Ok(None);
+                }
+                reading.id
+            };
+
+            debug!("Spool finished reading segment {}", finished);
+            let next = self.segments.iter().cloned().find(|id| *id > finished).unwrap_or(self.writing.id);
+            self.reading = Some(ReadingSegment::open(&self.dir, next, 0)?);
+        }
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        let position = match self.reading {
+            Some(ref reading) => (reading.id, reading.offset),
+            None => return Ok(()),
+        };
+        if position == self.committed {
+            return Ok(());
+        }
+
+        write_cursor(&self.dir, position)?;
+        self.committed = position;
+
+        while let Some(id) = self.segments.front().cloned() {
+            if id >= position.0 {
+                break;
+            }
+            self.segments.pop_front();
+            fs::remove_file(segment_path(&self.dir, id))?;
+            debug!("Spool removed delivered segment {}", id);
+        }
+
+        Ok(())
+    }
+}
+
+impl WritingSegment {
+    fn create(dir: &Path, id: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(segment_path(dir, id))?;
+        let size = file.metadata()?.len();
+
+        Ok(WritingSegment { id: id, file: file, size: size })
+    }
+}
+
+impl ReadingSegment {
+    fn open(dir: &Path, id: u64, offset: u64) -> io::Result<Self> {
+        let mut file = File::open(segment_path(dir, id))?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(ReadingSegment { id: id, reader: BufReader::new(file), offset: offset })
+    }
+
+    /**
+     * Only complete lines are returned. A sealed segment may end with a line truncated by a crash,
+     * which is skipped: it is never completed and a partial record cannot be delivered.
+     */
+    fn read_line(&mut self, sealed: bool) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let read = self.reader.read_line(&mut line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if !line.ends_with('\n') {
+            if sealed {
+                warn!("Skipping a truncated record at the end of spool segment {}", self.id);
+                self.offset += read as u64;
+            } else {
+                self.reader.seek(SeekFrom::Start(self.offset))?;
+            }
+            return Ok(None);
+        }
+
+        self.offset += read as u64;
+        line.pop();
+
+        Ok(Some(line))
+    }
+}
+
+impl Sink for SpoolWriter {
+    type SinkItem = Event;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, mut event: Event) -> StartSend<Event, io::Error> {
+        let receipt = event.receipt.take();
+        if let Err(err) = self.spool.inner.borrow_mut().append(&event.encode()) {
+            if let Some(receipt) = receipt {
+                receipt.fail();
+            }
+            return Err(err);
+        }
+        self.unsynced.extend(receipt);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        if !self.unsynced.is_empty() {
+            if let Err(err) = self.spool.inner.borrow().writing.file.sync_data() {
+                for receipt in &self.unsynced {
+                    receipt.fail();
+                }
+                self.unsynced.clear();
+                return Err(err);
+            }
+            self.unsynced.clear();
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for SpoolWriter {
+    fn drop(&mut self) {
+        let _ = self.poll_complete();
+        let mut inner = self.spool.inner.borrow_mut();
+        inner.closed = true;
+        if let Some(task) = inner.blocked_reader.take() {
+            task.notify();
+        }
+    }
+}
+
+impl SpoolReader {
+    /**
+     * Marks every line returned so far as delivered
+     */
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.spool.inner.borrow_mut().commit()
+    }
+
+    pub fn deliver<S>(self, sink: S) -> Delivery<S>
+        where S: Sink<SinkItem = Event, SinkError = io::Error>
+    {
+        Delivery {
+            reader: self,
+            sink: sink,
+            buffered: None,
+        }
+    }
+}
+
+impl Stream for SpoolReader {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, io::Error> {
+        let mut inner = self.spool.inner.borrow_mut();
+        loop {
+            match inner.read_line()? {
+                Some(line) => {
+                    match Event::decode(&line) {
+                        Ok(event) => return Ok(Async::Ready(Some(event))),
+                        /**
+                         * a record cannot be delivered if it cannot be read, such as a record
+                         * truncated by a crash
+                         */
+                        Err(err) => warn!("Skipping a spool record in {}: {}", inner.dir.display(), err),
+                    }
+                }
+                None if inner.closed => return Ok(Async::Ready(None)),
+                None => {
+                    inner.blocked_reader = Some(task::current());
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Like `Stream::forward`, but the spool is told which lines can be forgotten: every time the sink
+ * reports that all sent lines are complete, the read position is committed.
+ */
+impl<S> Future for Delivery<S>
+    where S: Sink<SinkItem = Event, SinkError = io::Error>
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            if let Some(event) = self.buffered.take() {
+                if let AsyncSink::NotReady(event) = self.sink.start_send(event)? {
+                    self.buffered = Some(event);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            if let Async::Ready(()) = self.sink.poll_complete()? {
+                self.reader.commit()?;
+            }
+
+            match self.reader.poll()? {
+                Async::Ready(Some(event)) => self.buffered = Some(event),
+                Async::Ready(None) => {
+                    /**
+                     * the writer is gone, done once everything read has been delivered
+                     */
+                    try_ready!(self.sink.poll_complete());
+                    self.reader.commit()?;
+                    return Ok(Async::Ready(()));
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/**
+ * The records a spool in `dir` holds, 0 when there is no spool there. Nothing is modified.
+ */
+pub fn undelivered_in(dir: &Path) -> io::Result<usize> {
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let segments = list_segments(dir)?;
+    let committed = read_cursor(dir)?.unwrap_or((segments.first().cloned().unwrap_or(0), 0));
+    count_undelivered(dir, segments.iter().filter(|id| **id >= committed.0), committed)
+}
+
+/**
+ * The ids of the segments in `dir`, in order
+ */
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) {
+            segments.push(id);
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn count_undelivered<'a, I>(dir: &Path, segments: I, committed: (u64, u64)) -> io::Result<usize>
+    where I: Iterator<Item = &'a u64>
+{
+    let mut undelivered = 0;
+    for id in segments {
+        let offset = if *id == committed.0 { committed.1 } else { 0 };
+        undelivered += count_records(&segment_path(dir, *id), offset)?;
+    }
+    Ok(undelivered)
+}
+
+/**
+ * The complete records after `offset` in a segment, a truncated last record is not read
+ */
+fn count_records(path: &Path, offset: u64) -> io::Result<usize> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut content = vec![];
+    file.read_to_end(&mut content)?;
+
+    Ok(content.iter().filter(|byte| **byte == b'\n').count())
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn read_cursor(dir: &Path) -> io::Result<Option<(u64, u64)>> {
+    let mut content = String::new();
+    match File::open(dir.join(CURSOR_FILE)) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut parts = content.split_whitespace().map(|part| part.parse::<u64>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(segment)), Some(Ok(offset))) => Ok(Some((segment, offset))),
+        _ => {
+            warn!("Spool cursor in {} is corrupted, replaying everything", dir.display());
+            Ok(None)
+        }
+    }
+}
+
+/**
+ * the cursor is written to a temporary file and then renamed, so that a crash never leaves a
+ * half written cursor behind
+ */
+fn write_cursor(dir: &Path, position: (u64, u64)) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", CURSOR_FILE));
+    {
+        let mut file = File::create(&tmp)?;
+        write!(file, "{} {}\n", position.0, position.1)?;
+    }
+    fs::rename(tmp, dir.join(CURSOR_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stubborn-sink-spool-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write(writer: &mut SpoolWriter, lines: &[&str]) {
+        for line in lines {
+            writer.start_send(Event::new(line.to_string())).unwrap();
+        }
+        writer.poll_complete().unwrap();
+    }
+
+    fn read(reader: &mut SpoolReader, count: u64) -> Vec<String> {
+        reader.by_ref().take(count).map(|event| event.line).collect().wait().unwrap()
+    }
+
+    #[test]
+    fn undelivered_lines_are_replayed_after_reopening() {
+        let dir = spool_dir("replay");
+        {
+            let (mut writer, mut reader) = Spool::open(&dir, 1024).unwrap().split();
+            write(&mut writer, &["one", "two", "three"]);
+            assert_eq!(read(&mut reader, 1), ["one"]);
+            reader.commit().unwrap();
+            assert_eq!(read(&mut reader, 1), ["two"]);
+        }
+
+        let spool = Spool::open(&dir, 1024).unwrap();
+        assert_eq!(spool.undelivered(), 2);
+        assert_eq!(undelivered_in(&dir).unwrap(), 2);
+        let (writer, mut reader) = spool.split();
+        drop(writer);
+        assert_eq!(read(&mut reader, 3), ["two", "three"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segments_are_removed_once_the_cursor_moves_past_them() {
+        let dir = spool_dir("segments");
+        let (mut writer, mut reader) = Spool::open(&dir, 1).unwrap().split();
+        write(&mut writer, &["one", "two", "three"]);
+        assert_eq!(list_segments(&dir).unwrap(), [1, 2, 3]);
+
+        assert_eq!(read(&mut reader, 2), ["one", "two"]);
+        assert_eq!(list_segments(&dir).unwrap(), [1, 2, 3]);
+        reader.commit().unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), [2, 3]);
+
+        assert_eq!(read(&mut reader, 1), ["three"]);
+        reader.commit().unwrap();
+        assert_eq!(list_segments(&dir).unwrap(), [3]);
+        assert_eq!(undelivered_in(&dir).unwrap(), 0);
+
+        drop(writer);
+        drop(reader);
+        let spool = Spool::open(&dir, 1).unwrap();
+        assert_eq!(spool.undelivered(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_line_truncated_by_a_crash_is_dropped() {
+        let dir = spool_dir("truncated");
+        fs::create_dir_all(&dir).unwrap();
+        let record = Event::new("complete".to_string()).encode();
+        let truncated = Event::new("truncated line".to_string()).encode();
+        let content = format!("{}\n{}", record, &truncated[..truncated.len() - 5]);
+        fs::write(segment_path(&dir, 0), content).unwrap();
+
+        let spool = Spool::open(&dir, 1024).unwrap();
+        assert_eq!(spool.undelivered(), 1);
+        let (mut writer, mut reader) = spool.split();
+        write(&mut writer, &["next"]);
+        drop(writer);
+        assert_eq!(read(&mut reader, 3), ["complete", "next"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_which_cannot_be_read_are_skipped() {
+        let dir = spool_dir("unreadable");
+        fs::create_dir_all(&dir).unwrap();
+        let record = Event::new("complete".to_string()).encode();
+        fs::write(segment_path(&dir, 0), format!("{}\n0;12\n", record)).unwrap();
+
+        let (writer, mut reader) = Spool::open(&dir, 1024).unwrap().split();
+        drop(writer);
+        assert_eq!(read(&mut reader, 3), ["complete"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,19 +1,36 @@
 use futures::future::Future;
 use futures::{Async, AsyncSink, Poll, StartSend, Stream, Sink};
-use futures::sync::mpsc::{self, UnboundedSender};
-// use futures::stream::{SplitSink};
-use tokio_core::io::Io;
-use tokio_core::net::{TcpStream, TcpStreamNew};
-use tokio_core::reactor::Handle;
-use tokio_line::LineCodec;
-use std::{self, io, str, fmt, thread, time};
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use futures::task::{self, Task};
+use futures::stream::SplitSink;
+use tokio_core::io::{Framed, Io};
+use tokio_core::reactor::{Handle, Timeout};
+use std::{self, io, str, fmt};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::string::String;
-use std::net::SocketAddr;
+use super::Conf;
+use backoff::Backoff;
+use codec::{Framing, RecordCodec};
+use event::Event;
+use format::Format;
+use resolve;
+use tls::TlsConnector;
+use unix;
+
+/**
+ * a connection staying up at least this long, in seconds, is considered healthy, the reconnection
+ * delay starts again from the initial one
+ */
+const STABLE_CONNECTION: u64 = 10;
 
 enum RemoteConnectionState {
     NotConnected,
-    Connecting(TcpStreamNew),
-    Connected(UnboundedSender<String>),
+    Connecting(Box<Future<Item = Connection, Error = io::Error>>),
+    Connected(Connection),
+    Waiting(Timeout),
 }
 
 impl fmt::Display for RemoteConnectionState {
@@ -22,80 +39,403 @@ impl fmt::Display for RemoteConnectionState {
             RemoteConnectionState::NotConnected => write!(f, "NotConnected"),
             RemoteConnectionState::Connecting(_) => write!(f, "Connecting"),
             RemoteConnectionState::Connected(_) => write!(f, "Connected"),
+            RemoteConnectionState::Waiting(_) => write!(f, "Waiting"),
+        }
+    }
+}
+
+/**
+ * What the spawned connection future tells back to the sink: how many lines have been flushed
+ * to the socket and which ones have been acknowledged since the last time the sink asked, and
+ * whether the connection is gone.
+ */
+struct Progress {
+    flushed: usize,
+    acks: Vec<Ack>,
+    closed: bool,
+    blocked_sink: Option<Task>,
+}
+
+/**
+ * In ack mode every line is sent as `<id> <line>` and the remote server answers on the same
+ * connection either with `ACK <id>`, for a single line, or with `ACK ..<id>`, for every line
+ * up to `<id>` included.
+ */
+#[derive(Debug, PartialEq)]
+enum Ack {
+    One(u64),
+    UpTo(u64),
+}
+
+impl str::FromStr for Ack {
+    type Err = ();
+
+    fn from_str(reply: &str) -> Result<Self, ()> {
+        let mut parts = reply.trim().splitn(2, ' ');
+        if parts.next() != Some("ACK") {
+            return Err(());
+        }
+
+        match parts.next().map(|id| id.trim()) {
+            Some(id) if id.starts_with("..") => id[2..].parse().map(Ack::UpTo).map_err(|_| ()),
+            Some(id) => id.parse().map(Ack::One).map_err(|_| ()),
+            None => Err(()),
+        }
+    }
+}
+
+struct InFlight {
+    seq: u64,
+    event: Event,
+    acked: bool,
+}
+
+impl InFlight {
+    fn encode(&self, ack: bool, format: &Format) -> String {
+        if ack {
+            format!("{} {}", self.seq, format.render(&self.event))
+        } else {
+            format.render(&self.event)
+        }
+    }
+}
+
+/**
+ * Marks the acknowledged lines and forgets every line at the front of the queue that has been
+ * acknowledged
+ */
+fn acknowledge(in_flight: &mut VecDeque<InFlight>, ack: Ack) {
+    match ack {
+        Ack::One(seq) => {
+            let first = match in_flight.front() {
+                Some(msg) => msg.seq,
+                None => return,
+            };
+            if seq >= first {
+                if let Some(msg) = in_flight.get_mut((seq - first) as usize) {
+                    msg.acked = true;
+                }
+            }
+        }
+        Ack::UpTo(seq) => {
+            for msg in in_flight.iter_mut().take_while(|msg| msg.seq <= seq) {
+                msg.acked = true;
+            }
+        }
+    }
+
+    while in_flight.front().map(|msg| msg.acked).unwrap_or(false) {
+        in_flight.pop_front();
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+/**
+ * Resolves when `address` accepts a connection, which is closed right away
+ */
+fn probe(address: &str, handle: &Handle) -> Box<Future<Item = (), Error = io::Error>> {
+    match unix::destination(address) {
+        Some(path) => Box::new(unix::connect(&path, handle).map(|_| ())),
+        None => Box::new(resolve::connect(address, handle).map(|_| ())),
+    }
+}
+
+impl Progress {
+    fn wake_sink(&mut self) {
+        if let Some(task) = self.blocked_sink.take() {
+            task.notify();
         }
     }
 }
 
+struct Connection {
+    middleware_tx: UnboundedSender<String>,
+    progress: Rc<RefCell<Progress>>,
+}
+
+impl Connection {
+    fn send(&self, msg: String) {
+        if self.middleware_tx.unbounded_send(msg).is_err() {
+            self.progress.borrow_mut().closed = true;
+        }
+    }
+
+    fn take_progress(&self) -> (usize, Vec<Ack>) {
+        let mut progress = self.progress.borrow_mut();
+        (std::mem::replace(&mut progress.flushed, 0), std::mem::replace(&mut progress.acks, vec![]))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.progress.borrow().closed
+    }
+
+    fn park(&self) {
+        self.progress.borrow_mut().blocked_sink = Some(task::current());
+    }
+}
+
+/**
+ * While connected to a secondary address, the primary one is periodically probed to fail back to it
+ */
+enum FailbackProbe {
+    Waiting(Timeout),
+    Connecting(Box<Future<Item = (), Error = io::Error>>),
+}
+
 pub struct StubbornSink {
-    remote_addr: std::net::SocketAddr,
+    /**
+     * addresses (`HOST:PORT` or `unix:PATH`) in failover order, the first one is the primary.
+     * Host names are resolved again on every connection attempt.
+     */
+    remote_addrs: Vec<String>,
+    current_addr: usize,
+    failed_attempts: u32,
+    failover_after: u32,
+    failback_interval: Duration,
+    failback_probe: Option<FailbackProbe>,
     status: RemoteConnectionState,
     handle: Handle,
+    ack: bool,
+    tls: Option<TlsConnector>,
+    framing: Framing,
+    format: Format,
+    backoff: Backoff,
+    /**
+     * when the current connection was established, until it has proved to be healthy by staying
+     * up or acknowledging lines
+     */
+    connected_at: Option<Instant>,
+    next_seq: u64,
+    /**
+     * lines handed to the current connection but not yet flushed to the socket (or acknowledged,
+     * in ack mode), they are sent again, before anything else, as soon as a new connection is
+     * established
+     */
+    in_flight: VecDeque<InFlight>,
+    max_in_flight: usize,
+    connect_timeout: Duration,
+    /**
+     * in ack mode, the connection is given up when nothing is acknowledged for this long while
+     * lines wait for it
+     */
+    ack_timeout: Duration,
+    ack_deadline: Option<Timeout>,
+    delivered: Rc<Cell<u64>>,
 }
 
 impl StubbornSink {
-    pub fn new(remote_addr: SocketAddr, handle: Handle) -> Self {
+    pub fn new(remote_addrs: Vec<String>, configuration: Conf, handle: Handle) -> Self {
         StubbornSink {
-            remote_addr: remote_addr,
+            remote_addrs: remote_addrs,
+            current_addr: 0,
+            failed_attempts: 0,
+            failover_after: configuration.failover_after,
+            failback_interval: configuration.failback_interval,
+            failback_probe: None,
             status: RemoteConnectionState::NotConnected,
             handle: handle,
+            ack: configuration.ack,
+            tls: configuration.tls_connector.clone(),
+            framing: configuration.output_framing,
+            format: configuration.output_format.clone(),
+            backoff: configuration.reconnect_backoff.clone(),
+            connected_at: None,
+            next_seq: 1,
+            in_flight: VecDeque::new(),
+            max_in_flight: configuration.max_in_flight,
+            connect_timeout: configuration.connect_timeout,
+            ack_timeout: configuration.ack_timeout,
+            ack_deadline: None,
+            delivered: Rc::new(Cell::new(0)),
         }
     }
 
-    fn connection_attempt(&mut self) -> TcpStreamNew {
-        TcpStream::connect(&self.remote_addr, &self.handle.clone())
+    /**
+     * Counter of the lines flushed to (or acknowledged by) the remote server
+     */
+    pub fn delivered(&self) -> Rc<Cell<u64>> {
+        self.delivered.clone()
+    }
+
+    pub fn name(&self) -> String {
+        self.remote_addrs.join(",")
+    }
+
+    /**
+     * True while waiting to retry after a failed connection attempt, or after the remote server
+     * stopped acknowledging lines
+     */
+    pub fn is_down(&self) -> bool {
+        match self.status {
+            RemoteConnectionState::Waiting(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.status {
+            RemoteConnectionState::Connected(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /**
+     * Gives up the lines not delivered yet, so that they can be sent somewhere else
+     */
+    pub fn take_undelivered(&mut self) -> Vec<Event> {
+        self.in_flight.drain(..).map(|msg| msg.event).collect()
+    }
+
+    /**
+     * A TLS handshake failure, or an attempt lasting more than `connect_timeout`, is a connection
+     * failure like any other
+     */
+    fn connection_attempt(&mut self) -> io::Result<Box<Future<Item = Connection, Error = io::Error>>> {
+        let timeout = Timeout::new(self.connect_timeout, &self.handle)?
+            .and_then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out")));
+        let attempt = self.connect().select(timeout).map(|(connection, _)| connection).map_err(|(err, _)| err);
+        Ok(Box::new(attempt))
+    }
+
+    fn connect(&mut self) -> Box<Future<Item = Connection, Error = io::Error>> {
+        let address = self.remote_addrs[self.current_addr].clone();
+        let handle = self.handle.clone();
+        let framing = self.framing;
+
+        /**
+         * Unix sockets are local, TLS only applies to TCP destinations
+         */
+        if let Some(path) = unix::destination(&address) {
+            return Box::new(unix::connect(&path, &handle).map(move |stream| StubbornSink::get_inner_sink(stream, framing, &handle)));
+        }
+
+        let connecting = resolve::connect(&address, &self.handle);
+        match self.tls {
+            None => Box::new(connecting.map(move |stream| StubbornSink::get_inner_sink(stream, framing, &handle))),
+            Some(ref tls) => {
+                let tls = tls.clone();
+                Box::new(connecting.and_then(move |stream| tls.connect(&address, stream))
+                    .map(move |stream| StubbornSink::get_inner_sink(stream, framing, &handle)))
+            }
+        }
+    }
+
+    /**
+     * Returns true as soon as the primary address accepts a connection again
+     */
+    fn poll_failback(&mut self) -> io::Result<bool> {
+        loop {
+            let next_probe = match self.failback_probe {
+                None => FailbackProbe::Waiting(Timeout::new(self.failback_interval, &self.handle)?),
+                Some(FailbackProbe::Waiting(ref mut timeout)) => {
+                    match timeout.poll()? {
+                        Async::NotReady => return Ok(false),
+                        Async::Ready(()) => {
+                            FailbackProbe::Connecting(probe(&self.remote_addrs[0], &self.handle))
+                        }
+                    }
+                }
+                Some(FailbackProbe::Connecting(ref mut future)) => {
+                    match future.poll() {
+                        Ok(Async::NotReady) => return Ok(false),
+                        Ok(Async::Ready(())) => break,
+                        Err(_) => FailbackProbe::Waiting(Timeout::new(self.failback_interval, &self.handle)?),
+                    }
+                }
+            };
+            self.failback_probe = Some(next_probe);
+        }
+
+        self.failback_probe = None;
+        Ok(true)
     }
 
     /**
     * I have failed to pass &self here, because the `match` `Connecting` branch locks self.
     * TODO:! Try using &self again!
     */
-    fn get_inner_sink(stream: TcpStream, handle: &Handle) -> UnboundedSender<String> {
+    fn get_inner_sink<S: Io + 'static>(stream: S, framing: Framing, handle: &Handle) -> Connection {
         let (middleware_tx, middleware_rx) = mpsc::unbounded::<String>();
+        let progress = Rc::new(RefCell::new(Progress {
+            flushed: 0,
+            acks: vec![],
+            closed: false,
+            blocked_sink: None,
+        }));
 
-        let (sender, receiver) = stream.framed(LineCodec).split();
+        /**
+         * acks are expected with the same framing as the lines
+         */
+        let (sender, receiver) = stream.framed(RecordCodec::new(framing)).split();
 
         /**
          * This is the only method that I have found to stop sending messages to the remote server after
          * it closed the connection, I don't like it, I would try to avoid the middleware_tx and
          * the reader future:
          *
-         * - reading data from remote server, even if it doesn't send anything, and even if I do not need that data.
+         * - reading data from remote server, even if it doesn't send anything, collecting the acks if any.
          *   This future ends properly when the connection is closed.
          * - create a middleware channel (unbound)
          * - send the data we would like to send to the remote server, to the middleware tx
-         * - forward data received on the middleware_rx to the remoteServer
+         * - forward data received on the middleware_rx to the remoteServer, counting the flushed lines
          * - link the reader future with the writer future, so that when the connection is closed the
          * reader future ends and so it stop also the writer future.
-         * - spawn the linked future
+         * - spawn the linked future, marking the connection as closed when it ends
          */
+        let acks_progress = progress.clone();
         let reader = receiver
-            .for_each(|_message| {
+            .for_each(move |message| {
+                match message.parse::<Ack>() {
+                    Ok(ack) => {
+                        let mut progress = acks_progress.borrow_mut();
+                        progress.acks.push(ack);
+                        progress.wake_sink();
+                    }
+                    Err(_) => debug!("Ignoring reply from remote server: {}", message),
+                }
                 Ok(())
             })
             .and_then(|_| {
-                //TODO:! try to update the sink status to NotConnected!
                 info!("Connection with remote server is lost");
                 Ok(())
             });
 
-        let writer = middleware_rx.map_err(|_| panic!("middleware_rx should never fails"))
-            .forward(sender)
-            .and_then(|(_rx, _tx)| Ok(()));
+        let writer = FlushingWriter {
+            middleware_rx: middleware_rx,
+            sender: sender,
+            buffered: None,
+            written: 0,
+            progress: progress.clone(),
+        };
 
-        let linked_future =
-            reader.select(writer).map_err(|(_err, _nf)| ()).and_then(move |_| Ok(()));
+        let closing_progress = progress.clone();
+        let linked_future = reader.select(writer)
+            .then(move |_| {
+                let mut progress = closing_progress.borrow_mut();
+                progress.closed = true;
+                progress.wake_sink();
+                Ok::<(), ()>(())
+            });
 
         handle.spawn(linked_future);
 
-        middleware_tx
+        Connection {
+            middleware_tx: middleware_tx,
+            progress: progress,
+        }
     }
-}
 
-impl Sink for StubbornSink {
-    type SinkItem = String;
-    type SinkError = io::Error;
-
-    fn start_send(&mut self, msg: String) -> StartSend<String, io::Error> {
+    /**
+     * Drives the connection state machine until the sink is connected or has to wait
+     */
+    pub fn poll_connection(&mut self) -> Poll<(), io::Error> {
         /**
          * I need a loop to handle current state and also the next state,
          * avoiding code duplication and recursion
@@ -103,38 +443,135 @@ impl Sink for StubbornSink {
         loop {
             debug!("current status: {}", self.status);
 
+            if let RemoteConnectionState::Connected(_) = self.status {
+                if self.current_addr != 0 && self.poll_failback()? {
+                    info!("Primary remote server {} is back, failing back", self.remote_addrs[0]);
+                    self.current_addr = 0;
+                    self.failed_attempts = 0;
+                    self.status = RemoteConnectionState::NotConnected;
+                }
+            }
+
             /**
              * current status cannot be updated "on the fly" because the enum is in "use"
              */
             let next_status = match self.status {
-                RemoteConnectionState::Connected(ref split_sink) => {
-                    match split_sink.send(msg.clone()) {
-                        Ok(_) => return Ok(AsyncSink::Ready),
-                        Err(_) => Some(RemoteConnectionState::NotConnected),
+                RemoteConnectionState::Connected(ref connection) => {
+                    let (flushed, acks) = connection.take_progress();
+                    let waiting = self.in_flight.len();
+                    let acked = !acks.is_empty();
+                    if self.ack {
+                        for ack in acks {
+                            acknowledge(&mut self.in_flight, ack);
+                        }
+                    } else {
+                        for _ in 0..flushed {
+                            self.in_flight.pop_front();
+                        }
+                    }
+                    self.delivered.set(self.delivered.get() + (waiting - self.in_flight.len()) as u64);
+
+                    let stable = self.connected_at
+                        .map(|connected_at| connected_at.elapsed() >= Duration::from_secs(STABLE_CONNECTION))
+                        .unwrap_or(false);
+                    if self.connected_at.is_some() && (acked || stable) {
+                        self.backoff.reset();
+                        self.connected_at = None;
+                    }
+
+                    if self.ack && !self.in_flight.is_empty() {
+                        if acked || self.ack_deadline.is_none() {
+                            self.ack_deadline = Some(Timeout::new(self.ack_timeout, &self.handle)?);
+                        }
+                    } else {
+                        self.ack_deadline = None;
+                    }
+                    let ack_timed_out = match self.ack_deadline {
+                        Some(ref mut deadline) => deadline.poll()?.is_ready(),
+                        None => false,
+                    };
+
+                    if ack_timed_out {
+                        /**
+                         * given up like a failed connection attempt, a balancer sends the lines
+                         * somewhere else meanwhile
+                         */
+                        warn!("Remote server {} acknowledged nothing for {}ms, dropping the connection",
+                              self.remote_addrs[self.current_addr],
+                              millis(self.ack_timeout));
+                        self.ack_deadline = None;
+                        let delay = self.backoff.next_delay();
+                        self.status = RemoteConnectionState::Waiting(Timeout::new(delay, &self.handle)?);
+                        continue;
+                    }
+
+                    if !connection.is_closed() {
+                        return Ok(Async::Ready(()));
+                    }
+
+                    if !self.in_flight.is_empty() {
+                        warn!("{} lines have not been flushed before the connection was lost, they will be sent again",
+                              self.in_flight.len());
+                    }
+                    if self.connected_at.take().is_none() {
+                        Some(RemoteConnectionState::NotConnected)
+                    } else {
+                        /**
+                         * a remote server dropping every connection right away is retried like
+                         * one refusing them
+                         */
+                        let delay = self.backoff.next_delay();
+                        warn!("Connection with remote server {} lost right away, reconnecting in {}ms",
+                              self.remote_addrs[self.current_addr],
+                              millis(delay));
+                        Some(RemoteConnectionState::Waiting(Timeout::new(delay, &self.handle)?))
                     }
                 }
                 RemoteConnectionState::Connecting(ref mut future) => {
                     match future.poll() {
-                        Err(_) => {
+                        Err(err) => {
                             /**
                              * If remote server is down, avoiding to "dos" it,
-                             * waiting a reasonable amount of time between retries
+                             * waiting a reasonable amount of time between retries.
+                             * The timer wakes this task up, so the reactor keeps serving the clients
                              */
-                            thread::sleep(time::Duration::from_millis(100)); //TODO:! make millis configurable
-                            Some(RemoteConnectionState::NotConnected)
+                            let delay = self.backoff.next_delay();
+                            warn!("Connection with remote server {} failed: {}, retrying in {}ms",
+                                  self.remote_addrs[self.current_addr],
+                                  err,
+                                  millis(delay));
+
+                            // after `failover_after` consecutive failures the next address in the list is tried
+                            self.failed_attempts += 1;
+                            if self.remote_addrs.len() > 1 && self.failed_attempts >= self.failover_after {
+                                self.failed_attempts = 0;
+                                self.failback_probe = None;
+                                self.current_addr = (self.current_addr + 1) % self.remote_addrs.len();
+                                warn!("Failing over to {}", self.remote_addrs[self.current_addr]);
+                            }
+                            Some(RemoteConnectionState::Waiting(Timeout::new(delay, &self.handle)?))
                         }
                         Ok(Async::NotReady) => {
-                            return Ok(AsyncSink::NotReady(msg));
+                            return Ok(Async::NotReady);
                         }
-                        Ok(Async::Ready(stream)) => {
-                            info!("Connection with remote server is successful");
-                            let middleware_tx = StubbornSink::get_inner_sink(stream, &self.handle); //TODO:! try to use self.get_inner_sink()
-                            Some(RemoteConnectionState::Connected(middleware_tx))
+                        Ok(Async::Ready(connection)) => {
+                            info!("Connection with remote server {} is successful", self.remote_addrs[self.current_addr]);
+                            self.connected_at = Some(Instant::now());
+                            self.failed_attempts = 0;
+                            self.ack_deadline = None;
+                            for msg in self.in_flight.iter() {
+                                connection.send(msg.encode(self.ack, &self.format));
+                            }
+                            Some(RemoteConnectionState::Connected(connection))
                         }
                     }
                 }
+                RemoteConnectionState::Waiting(ref mut timeout) => {
+                    try_ready!(timeout.poll());
+                    Some(RemoteConnectionState::NotConnected)
+                }
                 RemoteConnectionState::NotConnected => {
-                    Some(RemoteConnectionState::Connecting(self.connection_attempt()))
+                    Some(RemoteConnectionState::Connecting(self.connection_attempt()?))
                 }
             };
 
@@ -144,8 +581,400 @@ impl Sink for StubbornSink {
             }
         }
     }
+}
+
+impl Sink for StubbornSink {
+    type SinkItem = Event;
+    type SinkError = io::Error;
+
+    /**
+     * Once `max_in_flight` lines wait to be flushed (or acknowledged), no more lines are taken
+     * until the remote server catches up
+     */
+    fn start_send(&mut self, event: Event) -> StartSend<Event, io::Error> {
+        if let Async::NotReady = self.poll_connection()? {
+            return Ok(AsyncSink::NotReady(event));
+        }
+
+        if self.in_flight.len() >= self.max_in_flight {
+            if let RemoteConnectionState::Connected(ref connection) = self.status {
+                connection.park();
+            }
+            return Ok(AsyncSink::NotReady(event));
+        }
+
+        let msg = InFlight {
+            seq: self.next_seq,
+            event: event,
+            acked: false,
+        };
+        self.next_seq += 1;
+
+        if let RemoteConnectionState::Connected(ref connection) = self.status {
+            connection.send(msg.encode(self.ack, &self.format));
+        }
+        self.in_flight.push_back(msg);
 
+        Ok(AsyncSink::Ready)
+    }
+
+    /**
+     * It is complete only when every line sent so far has been flushed to the remote server (or
+     * acknowledged by it, in ack mode), reconnecting and sending them again if needed
+     */
     fn poll_complete(&mut self) -> Poll<(), io::Error> {
-        Ok(Async::Ready(()))
+        if self.in_flight.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        try_ready!(self.poll_connection());
+
+        if self.in_flight.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        if let RemoteConnectionState::Connected(ref connection) = self.status {
+            connection.park();
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/**
+ * Forwards lines from the middleware channel to the remote server like `Stream::forward` does, but
+ * it also counts the lines that have been flushed to the socket
+ */
+struct FlushingWriter<S> {
+    middleware_rx: UnboundedReceiver<String>,
+    sender: SplitSink<Framed<S, RecordCodec>>,
+    buffered: Option<String>,
+    written: usize,
+    progress: Rc<RefCell<Progress>>,
+}
+
+impl<S: Io> Future for FlushingWriter<S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            if let Some(msg) = self.buffered.take() {
+                match self.sender.start_send(msg)? {
+                    AsyncSink::Ready => self.written += 1,
+                    AsyncSink::NotReady(msg) => {
+                        self.buffered = Some(msg);
+                        break;
+                    }
+                }
+            }
+
+            match self.middleware_rx.poll() {
+                Ok(Async::Ready(Some(msg))) => self.buffered = Some(msg),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+                Err(_) => panic!("middleware_rx should never fails"),
+            }
+        }
+
+        try_ready!(self.sender.poll_complete());
+
+        if self.written > 0 {
+            let mut progress = self.progress.borrow_mut();
+            progress.flushed += self.written;
+            progress.wake_sink();
+            self.written = 0;
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, stream};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    fn parse(reply: &str) -> Option<(bool, u64)> {
+        match reply.parse::<Ack>() {
+            Ok(Ack::One(seq)) => Some((false, seq)),
+            Ok(Ack::UpTo(seq)) => Some((true, seq)),
+            Err(()) => None,
+        }
+    }
+
+    fn in_flight(seqs: &[u64]) -> VecDeque<InFlight> {
+        seqs.iter()
+            .map(|seq| {
+                InFlight {
+                    seq: *seq,
+                    event: Event::new(format!("line {}", seq)),
+                    acked: false,
+                }
+            })
+            .collect()
+    }
+
+    fn waiting(in_flight: &VecDeque<InFlight>) -> Vec<u64> {
+        in_flight.iter().map(|msg| msg.seq).collect()
+    }
+
+    #[test]
+    fn acks_are_parsed() {
+        assert_eq!(parse("ACK 5"), Some((false, 5)));
+        assert_eq!(parse("ACK ..7"), Some((true, 7)));
+        assert_eq!(parse("  ACK  3 \r"), Some((false, 3)));
+    }
+
+    #[test]
+    fn other_replies_are_not_acks() {
+        for reply in &["", "ACK", "ACK ", "ACK x", "ACK ..", "ACK ..x", "ACK -1", "ack 1", "NACK 1", "ACK 1 2"] {
+            assert_eq!(parse(reply), None, "{}", reply);
+        }
+    }
+
+    #[test]
+    fn lines_acked_out_of_order_wait_for_the_previous_ones() {
+        let mut lines = in_flight(&[10, 11, 12, 13]);
+
+        acknowledge(&mut lines, Ack::One(12));
+        assert_eq!(waiting(&lines), [10, 11, 12, 13]);
+
+        acknowledge(&mut lines, Ack::One(10));
+        assert_eq!(waiting(&lines), [11, 12, 13]);
+
+        acknowledge(&mut lines, Ack::One(11));
+        assert_eq!(waiting(&lines), [13]);
+    }
+
+    #[test]
+    fn cumulative_acks_cover_every_previous_line() {
+        let mut lines = in_flight(&[10, 11, 12, 13]);
+
+        acknowledge(&mut lines, Ack::UpTo(11));
+        assert_eq!(waiting(&lines), [12, 13]);
+
+        acknowledge(&mut lines, Ack::One(13));
+        acknowledge(&mut lines, Ack::UpTo(12));
+        assert!(lines.is_empty());
+    }
+
+    #[test]
+    fn acks_of_unknown_lines_are_ignored() {
+        let mut lines = in_flight(&[10, 11]);
+
+        acknowledge(&mut lines, Ack::One(9));
+        acknowledge(&mut lines, Ack::One(12));
+        acknowledge(&mut lines, Ack::UpTo(9));
+        assert_eq!(waiting(&lines), [10, 11]);
+
+        acknowledge(&mut lines, Ack::UpTo(20));
+        assert!(lines.is_empty());
+        acknowledge(&mut lines, Ack::One(10));
+        assert!(lines.is_empty());
+    }
+
+    /**
+     * Sends `count` lines to a sink connected to a remote server run by `serve` on its own thread
+     */
+    fn deliver<F, T>(args: &[&str], count: u64, serve: F) -> T
+        where F: FnOnce(TcpListener) -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || serve(listener));
+
+        let mut core = Core::new().unwrap();
+        let mut args = args.to_vec();
+        args.extend_from_slice(&["-d", &address, "--reconnect-delay", "10"]);
+        let sink = StubbornSink::new(vec![address.clone()], ::test_conf(&args), core.handle());
+        let events = stream::iter_ok::<_, io::Error>((1..count + 1).map(|seq| Event::new(format!("line {}", seq))));
+        core.run(sink.send_all(events)).unwrap();
+
+        server.join().unwrap()
+    }
+
+    fn read_line<R: BufRead>(reader: &mut R) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn unacknowledged_lines_are_sent_again_after_a_reconnect() {
+        let (lost, resent) = deliver(&["--ack"], 3, |listener| {
+            let mut lost = vec![];
+            {
+                let (socket, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(socket);
+                lost.push(read_line(&mut reader));
+                lost.push(read_line(&mut reader));
+                lost.push(read_line(&mut reader));
+            }
+
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            let resent = vec![read_line(&mut reader), read_line(&mut reader), read_line(&mut reader)];
+            socket.write_all(b"ACK ..3\n").unwrap();
+            (lost, resent)
+        });
+
+        assert_eq!(lost, ["1 line 1", "2 line 2", "3 line 3"]);
+        assert_eq!(resent, lost);
+    }
+
+    #[test]
+    fn no_more_lines_are_sent_while_the_window_is_full() {
+        let (held_back, released) = deliver(&["--ack", "--max-in-flight", "2"], 3, |listener| {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            assert_eq!(read_line(&mut reader), "1 line 1");
+            assert_eq!(read_line(&mut reader), "2 line 2");
+
+            socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut line = String::new();
+            let held_back = reader.read_line(&mut line).is_err();
+            socket.set_read_timeout(None).unwrap();
+
+            socket.write_all(b"ACK 1\n").unwrap();
+            let released = read_line(&mut reader);
+            socket.write_all(b"ACK ..3\n").unwrap();
+            (held_back, released)
+        });
+
+        assert!(held_back);
+        assert_eq!(released, "3 line 3");
+    }
+
+    #[test]
+    fn the_connection_is_dropped_when_nothing_is_acknowledged_in_time() {
+        let (ignored, acknowledged) = deliver(&["--ack", "--ack-timeout", "100"], 1, |listener| {
+            let ignored = {
+                let (socket, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(socket);
+                let ignored = read_line(&mut reader);
+                assert_eq!(read_line(&mut reader), "", "the connection is closed");
+                ignored
+            };
+
+            let (mut socket, _) = listener.accept().unwrap();
+            let acknowledged = read_line(&mut BufReader::new(socket.try_clone().unwrap()));
+            socket.write_all(b"ACK 1\n").unwrap();
+            (ignored, acknowledged)
+        });
+
+        assert_eq!(ignored, "1 line 1");
+        assert_eq!(acknowledged, "1 line 1");
+    }
+
+    #[test]
+    fn the_delay_keeps_growing_while_connections_are_dropped_right_away() {
+        let args = ["--ack", "--reconnect-multiplier", "2", "--reconnect-jitter", "0"];
+        let accepted = deliver(&args, 1, |listener| {
+            let mut accepted = vec![];
+            for _ in 0..4 {
+                drop(listener.accept().unwrap());
+                accepted.push(Instant::now());
+            }
+
+            let (mut socket, _) = listener.accept().unwrap();
+            accepted.push(Instant::now());
+            read_line(&mut BufReader::new(socket.try_clone().unwrap()));
+            socket.write_all(b"ACK 1\n").unwrap();
+            accepted
+        });
+
+        /**
+         * 10ms, 20ms, 40ms and 80ms
+         */
+        let waited = accepted.windows(2).map(|pair| pair[1].duration_since(pair[0])).collect::<Vec<_>>();
+        assert!(waited[3] >= Duration::from_millis(80), "{:?}", waited);
+        assert!(waited[0] + waited[1] + waited[2] + waited[3] >= Duration::from_millis(150), "{:?}", waited);
+    }
+
+    /**
+     * An address nothing listens on anymore
+     */
+    fn refused_address() -> String {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+    }
+
+    fn sink(addresses: &[&str], args: &[&str], core: &Core) -> StubbornSink {
+        let mut args = args.to_vec();
+        args.extend_from_slice(&["-d", addresses[0], "--reconnect-delay", "10"]);
+        let addresses = addresses.iter().map(|address| address.to_string()).collect();
+        StubbornSink::new(addresses, ::test_conf(&args), core.handle())
+    }
+
+    /**
+     * Drives the connection of `sink` until it is connected to the address at `index`
+     */
+    fn connect_to(sink: &mut StubbornSink, index: usize, core: &mut Core) {
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+                try_ready!(sink.poll_connection());
+                if sink.current_addr == index {
+                    Ok(Async::Ready(()))
+                } else {
+                    Ok(Async::NotReady)
+                }
+            }))
+            .unwrap();
+    }
+
+    /**
+     * Sends a line to the sink and reads it on `listener`, skipping the connections of the
+     * failback probes which send nothing
+     */
+    fn send_to(sink: StubbornSink, line: &str, listener: TcpListener, core: &mut Core) -> (StubbornSink, String) {
+        let reading = thread::spawn(move || {
+            loop {
+                let (socket, _) = listener.accept().unwrap();
+                let line = read_line(&mut BufReader::new(socket));
+                if !line.is_empty() {
+                    return line;
+                }
+            }
+        });
+        let sink = core.run(sink.send(Event::new(line.to_string()))).unwrap();
+        (sink, reading.join().unwrap())
+    }
+
+    #[test]
+    fn the_addresses_are_tried_in_order() {
+        let mut core = Core::new().unwrap();
+        let (first, second) = (refused_address(), refused_address());
+        let third = TcpListener::bind("127.0.0.1:0").unwrap();
+        let third_address = third.local_addr().unwrap().to_string();
+        let mut sink = sink(&[&first, &second, &third_address], &["--failover-after", "2"], &core);
+
+        connect_to(&mut sink, 2, &mut core);
+        assert!(sink.is_connected());
+        let (_, received) = send_to(sink, "failed over", third, &mut core);
+        assert_eq!(received, "failed over");
+    }
+
+    #[test]
+    fn the_primary_address_is_taken_back_once_it_is_up() {
+        let mut core = Core::new().unwrap();
+        let primary_address = refused_address();
+        let secondary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let secondary_address = secondary.local_addr().unwrap().to_string();
+        let args = ["--failover-after", "1", "--failback-interval", "20"];
+        let mut sink = sink(&[&primary_address, &secondary_address], &args, &core);
+
+        connect_to(&mut sink, 1, &mut core);
+        let (mut sink, received) = send_to(sink, "on the secondary", secondary, &mut core);
+        assert_eq!(received, "on the secondary");
+
+        let primary = TcpListener::bind(&primary_address[..]).unwrap();
+        connect_to(&mut sink, 0, &mut core);
+        assert!(sink.is_connected());
+        let (_, received) = send_to(sink, "back on the primary", primary, &mut core);
+        assert_eq!(received, "back on the primary");
     }
 }
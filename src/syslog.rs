@@ -0,0 +1,322 @@
+use codec::{Framing, OversizeCounts, RecordCodec, RecordLimit};
+use event::Event;
+use std::io;
+use tokio_core::io::{Codec, EasyBuf};
+
+const FACILITIES: [&'static str; 24] = ["kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp",
+                                        "cron", "authpriv", "ftp", "ntp", "security", "console", "solaris-cron",
+                                        "local0", "local1", "local2", "local3", "local4", "local5", "local6",
+                                        "local7"];
+
+const SEVERITIES: [&'static str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+                                    "Dec"];
+
+/**
+ * Syslog over a stream (RFC 6587): a message starting with a digit is octet counted
+ * (`LENGTH SP MESSAGE`), otherwise it ends at the newline
+ */
+pub struct SyslogCodec {
+    newline: RecordCodec,
+    octet_counting: RecordCodec,
+    octet_counted: bool,
+}
+
+impl SyslogCodec {
+    pub fn new(limit: Option<RecordLimit>, peer: String, oversize_counts: OversizeCounts) -> Self {
+        SyslogCodec {
+            newline: RecordCodec::new(Framing::Newline).with_limit(limit, peer.clone(), oversize_counts.clone()),
+            octet_counting: RecordCodec::new(Framing::OctetCounting).with_limit(limit, peer, oversize_counts),
+            octet_counted: false,
+        }
+    }
+}
+
+impl Codec for SyslogCodec {
+    type In = String;
+    type Out = String;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<String>> {
+        /**
+         * the framing can only change between messages
+         */
+        if self.newline.is_idle() && self.octet_counting.is_idle() {
+            self.octet_counted = buf.as_slice().first().map(|byte| *byte >= b'0' && *byte <= b'9').unwrap_or(false);
+        }
+
+        if self.octet_counted {
+            self.octet_counting.decode(buf)
+        } else {
+            self.newline.decode(buf)
+        }
+    }
+
+    fn encode(&mut self, message: String, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.octet_counting.encode(message, buf)
+    }
+}
+
+/**
+ * Parses a RFC 5424 or RFC 3164 message into fields, leaving the line as it was received.
+ *
+ * The fields are `facility`, `severity`, `priority`, `timestamp`, `hostname`, `app_name` and
+ * `message`, plus `procid`, `msgid` and `structured_data` when present. A line without a
+ * priority is not syslog and gets no field.
+ */
+pub fn parse(event: Event) -> Event {
+    let fields = match split_priority(&event.line) {
+        Some((priority, rest)) => {
+            let mut fields = vec![("priority", priority.to_string()),
+                                  ("facility", FACILITIES.get((priority >> 3) as usize).unwrap_or(&"unknown").to_string()),
+                                  ("severity", SEVERITIES[(priority & 7) as usize].to_string())];
+            if rest.starts_with("1 ") {
+                fields.extend(parse_rfc5424(&rest[2..]));
+            } else {
+                fields.extend(parse_rfc3164(rest));
+            }
+            fields
+        }
+        None => {
+            debug!("Not a syslog message: {}", event.line);
+            return event;
+        }
+    };
+
+    fields.into_iter().fold(event, |event, (key, value)| event.with_field(key, value))
+}
+
+/**
+ * `<PRI>rest`, PRI being at most 3 digits up to 191
+ */
+fn split_priority(line: &str) -> Option<(u8, &str)> {
+    if !line.starts_with('<') {
+        return None;
+    }
+    let end = match line[1..].find('>') {
+        Some(end) if end >= 1 && end <= 3 => end + 1,
+        _ => return None,
+    };
+
+    match line[1..end].parse::<u8>() {
+        Ok(priority) if priority <= 191 => Some((priority, &line[end + 1..])),
+        _ => None,
+    }
+}
+
+/**
+ * `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, `-` being a missing value
+ */
+fn parse_rfc5424(rest: &str) -> Vec<(&'static str, String)> {
+    let mut parts = rest.splitn(6, ' ');
+    let mut fields = vec![];
+
+    for name in &["timestamp", "hostname", "app_name", "procid", "msgid"] {
+        match parts.next() {
+            Some("-") | None => {}
+            Some(value) => fields.push((*name, value.to_string())),
+        }
+    }
+
+    let rest = parts.next().unwrap_or("");
+    let (structured_data, message) = split_structured_data(rest);
+    if structured_data != "-" && !structured_data.is_empty() {
+        fields.push(("structured_data", structured_data.to_string()));
+    }
+    fields.push(("message", message.trim_start_matches('\u{feff}').to_string()));
+
+    fields
+}
+
+/**
+ * `-` or one or more `[ID PARAM="VALUE" ...]`, where `]` can be escaped within values
+ */
+fn split_structured_data(rest: &str) -> (&str, &str) {
+    if !rest.starts_with('[') {
+        let mut parts = rest.splitn(2, ' ');
+        return (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    }
+
+    let mut escaped = false;
+    let mut in_element = false;
+    for (position, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => in_element = true,
+            ']' => in_element = false,
+            ' ' if !in_element => return (&rest[..position], &rest[position + 1..]),
+            _ => {}
+        }
+    }
+
+    (rest, "")
+}
+
+/**
+ * `Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG`, real world messages often lack the timestamp or the
+ * host name, what cannot be recognized is left in the message
+ */
+fn parse_rfc3164(rest: &str) -> Vec<(&'static str, String)> {
+    let mut fields = vec![];
+
+    let rest = if rest.len() > 16 && MONTHS.iter().any(|month| rest.starts_with(month)) &&
+                  rest.as_bytes()[15] == b' ' {
+        fields.push(("timestamp", rest[..15].to_string()));
+        let rest = &rest[16..];
+
+        match rest.find(' ') {
+            Some(space) if !rest[..space].ends_with(':') => {
+                fields.push(("hostname", rest[..space].to_string()));
+                &rest[space + 1..]
+            }
+            _ => rest,
+        }
+    } else {
+        rest
+    };
+
+    let tag_end = rest.find(|c: char| c == ':' || c == '[' || c == ' ');
+    let message = match tag_end {
+        Some(end) if end > 0 && &rest[end..end + 1] != " " => {
+            fields.push(("app_name", rest[..end].to_string()));
+            let after_tag = &rest[end..];
+            let after_pid = if after_tag.starts_with('[') {
+                match after_tag.find(']') {
+                    Some(close) => {
+                        fields.push(("procid", after_tag[1..close].to_string()));
+                        &after_tag[close + 1..]
+                    }
+                    None => after_tag,
+                }
+            } else {
+                after_tag
+            };
+            after_pid.trim_start_matches(':').trim_start_matches(' ')
+        }
+        _ => rest,
+    };
+    fields.push(("message", message.to_string()));
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn fields_of(line: &str) -> BTreeMap<String, String> {
+        parse(Event::new(line.to_string())).fields
+    }
+
+    fn fields(expected: &[(&str, &str)]) -> BTreeMap<String, String> {
+        expected.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn priority_is_split_from_the_message() {
+        assert_eq!(split_priority("<34>1 rest"), Some((34, "1 rest")));
+        assert_eq!(split_priority("<0>rest"), Some((0, "rest")));
+        assert_eq!(split_priority("<191>"), Some((191, "")));
+    }
+
+    #[test]
+    fn invalid_priorities_are_not_syslog() {
+        assert_eq!(split_priority("34>rest"), None);
+        assert_eq!(split_priority("<>rest"), None);
+        assert_eq!(split_priority("<192>rest"), None);
+        assert_eq!(split_priority("<1234>rest"), None);
+        assert_eq!(split_priority("<3a>rest"), None);
+        assert_eq!(split_priority("<34 rest"), None);
+    }
+
+    #[test]
+    fn rfc5424_message() {
+        let line = "<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 - \u{feff}'su root' failed for \
+                    lonvick on /dev/pts/8";
+        assert_eq!(fields_of(line),
+                   fields(&[("priority", "34"),
+                            ("facility", "auth"),
+                            ("severity", "crit"),
+                            ("timestamp", "2003-10-11T22:14:15.003Z"),
+                            ("hostname", "mymachine.example.com"),
+                            ("app_name", "su"),
+                            ("msgid", "ID47"),
+                            ("message", "'su root' failed for lonvick on /dev/pts/8")]));
+    }
+
+    #[test]
+    fn rfc5424_message_with_structured_data() {
+        let line = "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog 1234 ID47 [exampleSDID@32473 \
+                    iut=\"3\" eventSource=\"Appli]cation\\]\"][examplePriority@32473 class=\"high\"] An application \
+                    event log entry";
+        assert_eq!(fields_of(line),
+                   fields(&[("priority", "165"),
+                            ("facility", "local4"),
+                            ("severity", "notice"),
+                            ("timestamp", "2003-10-11T22:14:15.003Z"),
+                            ("hostname", "mymachine.example.com"),
+                            ("app_name", "evntslog"),
+                            ("procid", "1234"),
+                            ("msgid", "ID47"),
+                            ("structured_data",
+                             "[exampleSDID@32473 iut=\"3\" eventSource=\"Appli]cation\\]\"][examplePriority@32473 \
+                              class=\"high\"]"),
+                            ("message", "An application event log entry")]));
+    }
+
+    #[test]
+    fn rfc5424_message_with_nil_values_and_no_message() {
+        assert_eq!(fields_of("<14>1 - - - - - -"),
+                   fields(&[("priority", "14"), ("facility", "user"), ("severity", "info"), ("message", "")]));
+    }
+
+    #[test]
+    fn rfc3164_message() {
+        assert_eq!(fields_of("<34>Oct 11 22:14:15 mymachine su: 'su root' failed for lonvick on /dev/pts/8"),
+                   fields(&[("priority", "34"),
+                            ("facility", "auth"),
+                            ("severity", "crit"),
+                            ("timestamp", "Oct 11 22:14:15"),
+                            ("hostname", "mymachine"),
+                            ("app_name", "su"),
+                            ("message", "'su root' failed for lonvick on /dev/pts/8")]));
+    }
+
+    #[test]
+    fn rfc3164_message_with_pid_and_padded_day() {
+        assert_eq!(fields_of("<13>Feb  5 17:32:18 10.0.0.99 sshd[1234]: Accepted publickey"),
+                   fields(&[("priority", "13"),
+                            ("facility", "user"),
+                            ("severity", "notice"),
+                            ("timestamp", "Feb  5 17:32:18"),
+                            ("hostname", "10.0.0.99"),
+                            ("app_name", "sshd"),
+                            ("procid", "1234"),
+                            ("message", "Accepted publickey")]));
+    }
+
+    #[test]
+    fn rfc3164_message_without_header() {
+        assert_eq!(fields_of("<13>cron[42]: job started"),
+                   fields(&[("priority", "13"),
+                            ("facility", "user"),
+                            ("severity", "notice"),
+                            ("app_name", "cron"),
+                            ("procid", "42"),
+                            ("message", "job started")]));
+        assert_eq!(fields_of("<13>just a message"),
+                   fields(&[("priority", "13"),
+                            ("facility", "user"),
+                            ("severity", "notice"),
+                            ("message", "just a message")]));
+    }
+
+    #[test]
+    fn lines_without_priority_get_no_field() {
+        let event = parse(Event::new("Oct 11 22:14:15 mymachine su: failed".to_string()));
+        assert!(event.fields.is_empty());
+        assert_eq!(event.line, "Oct 11 22:14:15 mymachine su: failed");
+    }
+}
@@ -0,0 +1,502 @@
+use event::{Event, Receipt};
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use glob::glob;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Interval};
+
+/**
+ * lines read from a single file before moving to the next one, so that a large file does not
+ * delay the others nor fill the memory
+ */
+const MAX_LINES_PER_SCAN: usize = 1000;
+
+/**
+ * bytes at the start of a file whose hash tells it apart from another file which got the same
+ * inode
+ */
+const HEAD_SIZE: usize = 1024;
+
+/**
+ * a file is identified by device and inode, so that it is recognized after being renamed
+ */
+type FileId = (u64, u64);
+
+/**
+ * length and hash of the first bytes of a file, up to `HEAD_SIZE`
+ */
+type Head = (u64, u64);
+
+/**
+ * Where a file was left, as read from the state file
+ */
+struct SavedPosition {
+    offset: u64,
+    /**
+     * missing from the state files written by the previous versions
+     */
+    head: Option<Head>,
+}
+
+struct TailedFile {
+    path: PathBuf,
+    reader: BufReader<File>,
+    /**
+     * position after the last complete line read
+     */
+    position: u64,
+    /**
+     * position after the last line taken care of, spooled or delivered, which is what is persisted
+     */
+    committed: u64,
+    partial: Vec<u8>,
+    head: Head,
+}
+
+/**
+ * The lines appended to the files matching some paths or globs.
+ *
+ * Files are checked periodically: a file renamed away is read to its end and then forgotten, a
+ * new file appearing at a watched path is read from its start, a truncated file is read again
+ * from its start. The position of every file is persisted in `state_file` so that after a
+ * restart each file is resumed where it was left, unless it is now shorter or starts differently,
+ * being another file which got the same inode.
+ */
+pub struct Tail {
+    patterns: Vec<String>,
+    state_file: PathBuf,
+    files: BTreeMap<FileId, TailedFile>,
+    /**
+     * positions read from the state file, the ones of the files not found by the first scan are
+     * forgotten
+     */
+    saved: BTreeMap<FileId, SavedPosition>,
+    pending: VecDeque<Event>,
+    /**
+     * for every batch of lines handed over, in order, the position after it and the receipt of
+     * its lines
+     */
+    acks: VecDeque<(FileId, u64, oneshot::Receiver<bool>)>,
+    /**
+     * set once some lines could not be spooled: the positions stay where they are, so that these
+     * lines are read again after a restart
+     */
+    stalled: bool,
+    interval: Interval,
+    rescan: bool,
+    /**
+     * whether some position changed since the state was saved
+     */
+    dirty: bool,
+}
+
+impl Tail {
+    pub fn new(patterns: Vec<String>, state_file: PathBuf, interval: Duration, handle: &Handle) -> io::Result<Self> {
+        let saved = read_state(&state_file)?;
+
+        Ok(Tail {
+            patterns: patterns,
+            state_file: state_file,
+            files: BTreeMap::new(),
+            saved: saved,
+            pending: VecDeque::new(),
+            acks: VecDeque::new(),
+            stalled: false,
+            interval: Interval::new(interval, handle)?,
+            rescan: true,
+            dirty: false,
+        })
+    }
+
+    fn scan(&mut self) -> io::Result<()> {
+        let mut found = BTreeSet::new();
+        for path in self.matching_paths() {
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let id = (metadata.dev(), metadata.ino());
+            found.insert(id);
+            if !self.files.contains_key(&id) {
+                let saved = self.saved.remove(&id);
+                self.follow(id, path, saved)?;
+            }
+        }
+
+        if !self.saved.is_empty() {
+            info!("Forgetting the positions of {} files which are gone", self.saved.len());
+            self.saved.clear();
+            self.dirty = true;
+        }
+
+        self.rescan = false;
+        let ids = self.files.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            let at_end = self.read_lines(id)?;
+            if !at_end {
+                self.rescan = true;
+            } else if !found.contains(&id) && !self.acks.iter().any(|&(ack_id, _, _)| ack_id == id) {
+                let file = self.files.remove(&id).expect("a followed file");
+                self.dirty = true;
+                info!("Stopped following {}, it has been rotated", file.path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn matching_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![];
+        for pattern in &self.patterns {
+            match glob(pattern) {
+                Ok(matches) => paths.extend(matches.filter_map(|path| path.ok())),
+                Err(err) => warn!("Invalid pattern {}: {}", pattern, err),
+            }
+        }
+        paths
+    }
+
+    fn follow(&mut self, id: FileId, path: PathBuf, saved: Option<SavedPosition>) -> io::Result<()> {
+        let mut file = File::open(&path)?;
+        let head = read_head(&file, HEAD_SIZE)?;
+        let position = match saved {
+            None => 0,
+            Some(saved) => {
+                let same_head = match saved.head {
+                    Some((length, hash)) => read_head(&file, length as usize)? == (length, hash),
+                    None => true,
+                };
+                if !same_head {
+                    warn!("{} is not the file that was left, reading it from the start", path.display());
+                    0
+                } else if file.metadata()?.len() < saved.offset {
+                    warn!("{} is shorter than where it was left, reading it from the start", path.display());
+                    0
+                } else {
+                    saved.offset
+                }
+            }
+        };
+        file.seek(SeekFrom::Start(position))?;
+
+        info!("Following {} from offset {}", path.display(), position);
+        self.files.insert(id,
+                          TailedFile {
+                              path: path,
+                              reader: BufReader::new(file),
+                              position: position,
+                              committed: position,
+                              partial: Vec::new(),
+                              head: head,
+                          });
+        Ok(())
+    }
+
+    /**
+     * Reads the complete lines of a file, returns whether its end has been reached
+     */
+    fn read_lines(&mut self, id: FileId) -> io::Result<bool> {
+        let file = self.files.get_mut(&id).expect("a followed file");
+
+        let length = file.reader.get_ref().metadata()?.len();
+        if length < file.position {
+            warn!("{} has been truncated, reading it from the start", file.path.display());
+            file.reader.seek(SeekFrom::Start(0))?;
+            file.position = 0;
+            file.committed = 0;
+            file.partial.clear();
+            file.head = read_head(file.reader.get_ref(), HEAD_SIZE)?;
+            self.acks.retain(|&(ack_id, _, _)| ack_id != id);
+            self.dirty = true;
+        }
+
+        let (receipt, ack) = Receipt::new();
+        let mut at_end = false;
+        let start = file.position;
+        for _ in 0..MAX_LINES_PER_SCAN {
+            if file.reader.read_until(b'\n', &mut file.partial)? == 0 || !file.partial.ends_with(b"\n") {
+                at_end = true;
+                break;
+            }
+
+            file.position += file.partial.len() as u64;
+            let line = String::from_utf8_lossy(&file.partial).trim_end_matches(&['\r', '\n'][..]).to_string();
+            file.partial.clear();
+
+            let event = Event::new(line)
+                .received_on("tail", None)
+                .with_field("path", file.path.to_string_lossy().into_owned())
+                .with_receipt(receipt.clone());
+            self.pending.push_back(event);
+        }
+
+        if file.position > start {
+            self.acks.push_back((id, file.position, ack));
+            if file.head.0 < HEAD_SIZE as u64 {
+                file.head = read_head(file.reader.get_ref(), HEAD_SIZE)?;
+            }
+        }
+        Ok(at_end)
+    }
+
+    /**
+     * Moves the position of the files past the lines that have been taken care of
+     */
+    fn acknowledge(&mut self) {
+        while !self.stalled {
+            let (id, position, mut ack) = match self.acks.pop_front() {
+                Some(ack) => ack,
+                None => return,
+            };
+            match ack.poll() {
+                Ok(Async::NotReady) => {
+                    self.acks.push_front((id, position, ack));
+                    return;
+                }
+                Ok(Async::Ready(true)) => {
+                    if let Some(file) = self.files.get_mut(&id) {
+                        file.committed = position;
+                        self.dirty = true;
+                    }
+                }
+                Ok(Async::Ready(false)) | Err(_) => {
+                    error!("Lines of a followed file could not be spooled, the positions are not saved anymore");
+                    self.stalled = true;
+                }
+            }
+        }
+    }
+
+    /**
+     * the state is written to a temporary file and then renamed, as the spool cursor
+     */
+    fn save_state(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.dirty = false;
+
+        let tmp = self.state_file.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for (&(dev, ino), file_state) in &self.files {
+                let (head_length, head_hash) = file_state.head;
+                write!(file, "{} {} {} {} {}\n", dev, ino, file_state.committed, head_length, head_hash)?;
+            }
+        }
+        fs::rename(tmp, &self.state_file)
+    }
+}
+
+impl Stream for Tail {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, io::Error> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+
+            /**
+             * everything read so far has been handed over, the position is saved as far as the
+             * lines have been taken care of
+             */
+            self.acknowledge();
+            if !self.rescan {
+                self.save_state()?;
+                if let Async::NotReady = self.interval.poll()? {
+                    return Ok(Async::NotReady);
+                }
+            }
+            self.scan()?;
+        }
+    }
+}
+
+/**
+ * `DEVICE INODE OFFSET HEAD_LENGTH HEAD_HASH` on every line, the head being optional
+ */
+fn read_state(path: &Path) -> io::Result<BTreeMap<FileId, SavedPosition>> {
+    let mut content = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut saved = BTreeMap::new();
+    for line in content.lines() {
+        let parts = line.split_whitespace().map(|part| part.parse::<u64>()).collect::<Result<Vec<_>, _>>();
+        let (id, position) = match parts.as_ref().map(|parts| &parts[..]) {
+            Ok(&[dev, ino, offset]) => {
+                ((dev, ino),
+                 SavedPosition {
+                    offset: offset,
+                    head: None,
+                })
+            }
+            Ok(&[dev, ino, offset, head_length, head_hash]) => {
+                ((dev, ino),
+                 SavedPosition {
+                    offset: offset,
+                    head: Some((head_length, head_hash)),
+                })
+            }
+            _ => {
+                warn!("Ignoring corrupted line `{}` of {}", line, path.display());
+                continue;
+            }
+        };
+        saved.insert(id, position);
+    }
+
+    Ok(saved)
+}
+
+/**
+ * Length and hash of the first `limit` bytes of `file`, or less when it is shorter
+ */
+fn read_head(file: &File, limit: usize) -> io::Result<Head> {
+    let mut head = vec![0; limit];
+    let mut length = 0;
+    while length < limit {
+        match file.read_at(&mut head[length..], length as u64)? {
+            0 => break,
+            read => length += read,
+        }
+    }
+    Ok((length as u64, fnv1a(&head[..length])))
+}
+
+/**
+ * FNV-1a, which stays the same across builds, as the hash is persisted
+ */
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::process;
+    use tokio_core::reactor::Core;
+
+    fn tail_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stubborn-sink-tail-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn tail(dir: &Path, core: &Core) -> Tail {
+        let pattern = dir.join("app.log").to_string_lossy().into_owned();
+        Tail::new(vec![pattern], dir.join("state"), Duration::from_millis(10), &core.handle()).unwrap()
+    }
+
+    fn append(path: &Path, text: &str) {
+        OpenOptions::new().create(true).append(true).open(path).unwrap().write_all(text.as_bytes()).unwrap();
+    }
+
+    /**
+     * Reads `count` lines and saves the position after them
+     */
+    fn read(tail: &mut Tail, core: &mut Core, count: u64) -> Vec<String> {
+        let mut lines = core.run(tail.by_ref().take(count).map(|event| event.line).collect()).unwrap();
+        tail.acknowledge();
+        tail.save_state().unwrap();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn files_are_resumed_where_they_were_left() {
+        let dir = tail_dir("resume");
+        let mut core = Core::new().unwrap();
+        append(&dir.join("app.log"), "one\ntwo\n");
+        assert_eq!(read(&mut tail(&dir, &core), &mut core, 2), ["one", "two"]);
+
+        append(&dir.join("app.log"), "three\n");
+        assert_eq!(read(&mut tail(&dir, &core), &mut core, 1), ["three"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_rotated_file_is_read_to_its_end() {
+        let dir = tail_dir("rotation");
+        let mut core = Core::new().unwrap();
+        let mut tail = tail(&dir, &core);
+        append(&dir.join("app.log"), "one\ntwo\n");
+        assert_eq!(read(&mut tail, &mut core, 2), ["one", "two"]);
+
+        fs::rename(dir.join("app.log"), dir.join("app.log.1")).unwrap();
+        append(&dir.join("app.log.1"), "three\n");
+        append(&dir.join("app.log"), "new\n");
+        assert_eq!(read(&mut tail, &mut core, 2), ["new", "three"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_truncated_file_is_read_again_from_its_start() {
+        let dir = tail_dir("truncation");
+        let mut core = Core::new().unwrap();
+        let mut tail = tail(&dir, &core);
+        append(&dir.join("app.log"), "one\ntwo\n");
+        assert_eq!(read(&mut tail, &mut core, 2), ["one", "two"]);
+
+        File::create(dir.join("app.log")).unwrap().write_all(b"x\n").unwrap();
+        assert_eq!(read(&mut tail, &mut core, 1), ["x"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_file_starting_differently_is_read_from_its_start() {
+        let dir = tail_dir("identity");
+        let mut core = Core::new().unwrap();
+        append(&dir.join("app.log"), "one\ntwo\n");
+        assert_eq!(read(&mut tail(&dir, &core), &mut core, 2), ["one", "two"]);
+
+        /**
+         * rewritten in place, it keeps its inode and gets longer than the saved position
+         */
+        File::create(dir.join("app.log")).unwrap().write_all(b"other\nlines\n").unwrap();
+        assert_eq!(read(&mut tail(&dir, &core), &mut core, 2), ["lines", "other"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_positions_of_missing_files_are_forgotten() {
+        let dir = tail_dir("prune");
+        let mut core = Core::new().unwrap();
+        File::create(dir.join("state")).unwrap().write_all(b"1 2 30\n3 4 50 10 12345\n").unwrap();
+        append(&dir.join("app.log"), "one\n");
+        assert_eq!(read(&mut tail(&dir, &core), &mut core, 1), ["one"]);
+
+        let saved = read_state(&dir.join("state")).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert!(!saved.contains_key(&(1, 2)) && !saved.contains_key(&(3, 4)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_files_without_heads_are_read() {
+        let dir = tail_dir("state");
+        File::create(dir.join("state")).unwrap().write_all(b"1 2 30\n3 4 50 10 12345\ncorrupted\n").unwrap();
+        let saved = read_state(&dir.join("state")).unwrap();
+        assert_eq!((saved[&(1, 2)].offset, saved[&(1, 2)].head), (30, None));
+        assert_eq!((saved[&(3, 4)].offset, saved[&(3, 4)].head), (50, Some((10, 12345))));
+        assert_eq!(saved.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
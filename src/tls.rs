@@ -0,0 +1,219 @@
+use futures::future::{self, Future};
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+use resolve;
+use std::io::{self, ErrorKind, Read, Write};
+use std::str::FromStr;
+use tokio_core::io::Io;
+use tokio_core::net::TcpStream;
+use tokio_openssl::{ConnectConfigurationExt, SslAcceptorExt, SslStream};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification {
+    /**
+     * the certificate chain and the host name are both verified
+     */
+    Full,
+    /**
+     * only the certificate chain is verified, SNI is sent only when overridden
+     */
+    NoHostname,
+    None,
+}
+
+impl FromStr for Verification {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode {
+            "full" => Ok(Verification::Full),
+            "no-hostname" => Ok(Verification::NoHostname),
+            "none" => Ok(Verification::None),
+            other => Err(format!("unknown TLS verification mode `{}`", other)),
+        }
+    }
+}
+
+/**
+ * TLS settings for the connection to the remote server
+ */
+#[derive(Clone)]
+pub struct TlsConnector {
+    connector: SslConnector,
+    sni: Option<String>,
+    verification: Verification,
+}
+
+impl TlsConnector {
+    pub fn new(ca_file: Option<String>,
+               cert_file: Option<String>,
+               key_file: Option<String>,
+               sni: Option<String>,
+               verification: Verification)
+               -> Result<Self, String> {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|err| err.to_string())?;
+        {
+            let context = &mut *builder;
+            if let Some(ref ca_file) = ca_file {
+                context.set_ca_file(ca_file).map_err(|err| format!("cannot load {}: {}", ca_file, err))?;
+            }
+            match (cert_file, key_file) {
+                (Some(cert_file), Some(key_file)) => {
+                    context.set_certificate_chain_file(&cert_file)
+                        .map_err(|err| format!("cannot load {}: {}", cert_file, err))?;
+                    context.set_private_key_file(&key_file, SslFiletype::PEM)
+                        .map_err(|err| format!("cannot load {}: {}", key_file, err))?;
+                    context.check_private_key().map_err(|err| format!("{} does not match {}: {}", key_file, cert_file, err))?;
+                }
+                (None, None) => {}
+                _ => return Err("a client certificate requires both --tls-cert and --tls-key".to_string()),
+            }
+            if verification == Verification::None {
+                context.set_verify(SslVerifyMode::NONE);
+            }
+        }
+
+        Ok(TlsConnector {
+            connector: builder.build(),
+            sni: sni,
+            verification: verification,
+        })
+    }
+
+    /**
+     * Performs the handshake over an already connected `stream`; `address` is the `HOST:PORT`
+     * the stream is connected to, its host is used for SNI and verification unless overridden.
+     *
+     * Without host name verification SNI is only sent when it is overridden.
+     */
+    pub fn connect(&self, address: &str, stream: TcpStream) -> Box<Future<Item = TlsStream, Error = io::Error>> {
+        let mut configuration = match self.connector.configure() {
+            Ok(configuration) => configuration,
+            Err(err) => return Box::new(future::err(io::Error::new(ErrorKind::Other, format!("TLS setup failed: {}", err)))),
+        };
+
+        let domain = match self.sni {
+            Some(ref sni) => sni.clone(),
+            None => resolve::host(address).to_string(),
+        };
+        if self.verification != Verification::Full {
+            configuration.set_use_server_name_indication(self.sni.is_some());
+            configuration.set_verify_hostname(false);
+        }
+
+        let handshake = configuration.connect_async(&domain, stream)
+            .map(TlsStream)
+            .map_err(|err| io::Error::new(ErrorKind::Other, format!("TLS handshake failed: {}", err)));
+
+        Box::new(handshake)
+    }
+}
+
+/**
+ * TLS settings of the listener clients connect to
+ */
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: SslAcceptor,
+}
+
+impl TlsAcceptor {
+    /**
+     * Clients presenting a certificate are verified against `client_ca`; with
+     * `require_client_cert` clients without a certificate are refused
+     */
+    pub fn new(cert_file: &str,
+               key_file: &str,
+               client_ca: Option<String>,
+               require_client_cert: bool)
+               -> Result<Self, String> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|err| err.to_string())?;
+        {
+            let context = &mut *builder;
+            context.set_certificate_chain_file(cert_file)
+                .map_err(|err| format!("cannot load {}: {}", cert_file, err))?;
+            context.set_private_key_file(key_file, SslFiletype::PEM)
+                .map_err(|err| format!("cannot load {}: {}", key_file, err))?;
+            context.check_private_key().map_err(|err| format!("{} does not match {}: {}", key_file, cert_file, err))?;
+
+            match client_ca {
+                Some(ref client_ca) => {
+                    context.set_ca_file(client_ca).map_err(|err| format!("cannot load {}: {}", client_ca, err))?;
+                    if require_client_cert {
+                        context.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+                    } else {
+                        context.set_verify(SslVerifyMode::PEER);
+                    }
+                }
+                None if require_client_cert => {
+                    return Err("requiring client certificates needs --listen-tls-client-ca".to_string())
+                }
+                None => {}
+            }
+        }
+
+        Ok(TlsAcceptor { acceptor: builder.build() })
+    }
+
+    /**
+     * Performs the handshake, returning the stream and the subject of the client certificate
+     */
+    pub fn accept(&self, stream: TcpStream) -> Box<Future<Item = (TlsStream, Option<String>), Error = io::Error>> {
+        let handshake = self.acceptor
+            .accept_async(stream)
+            .map(|stream| {
+                let subject = stream.get_ref().ssl().peer_certificate().map(|certificate| subject(&certificate));
+                (TlsStream(stream), subject)
+            })
+            .map_err(|err| io::Error::new(ErrorKind::Other, format!("TLS handshake failed: {}", err)));
+
+        Box::new(handshake)
+    }
+}
+
+/**
+ * tokio-openssl streams implement the tokio-io traits, this makes them usable with `Io::framed`
+ */
+pub struct TlsStream(SslStream<TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Io for TlsStream {}
+
+/**
+ * `CN=...,OU=...,O=...,L=...,ST=...,C=...`, skipping the missing entries
+ */
+fn subject(certificate: &X509) -> String {
+    let entries = [("CN", Nid::COMMONNAME),
+                   ("OU", Nid::ORGANIZATIONALUNITNAME),
+                   ("O", Nid::ORGANIZATIONNAME),
+                   ("L", Nid::LOCALITYNAME),
+                   ("ST", Nid::STATEORPROVINCENAME),
+                   ("C", Nid::COUNTRYNAME)];
+
+    entries.iter()
+        .flat_map(|&(name, nid)| {
+            certificate.subject_name()
+                .entries_by_nid(nid)
+                .filter_map(|entry| entry.data().as_utf8().ok())
+                .map(move |value| format!("{}={}", name, &*value))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
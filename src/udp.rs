@@ -0,0 +1,125 @@
+use event::Event;
+use futures::{Async, Poll, Stream};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use tokio_core::net::UdpSocket;
+
+/**
+ * largest payload of a UDP datagram over IPv4
+ */
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/**
+ * The records received on a UDP socket: every newline-separated record of a datagram is a line,
+ * a datagram without newlines is a single line.
+ *
+ * Datagrams longer than `max_size` are truncated to it and counted.
+ */
+pub struct Datagrams {
+    socket: UdpSocket,
+    /**
+     * one byte more than the maximum size, so that a longer datagram can be told apart
+     */
+    buffer: Vec<u8>,
+    max_size: usize,
+    records: VecDeque<Event>,
+    oversized: u64,
+}
+
+impl Datagrams {
+    pub fn new(socket: UdpSocket, max_size: usize) -> Self {
+        Datagrams {
+            socket: socket,
+            buffer: vec![0; max_size + 1],
+            max_size: max_size,
+            records: VecDeque::new(),
+            oversized: 0,
+        }
+    }
+
+    fn split(&mut self, size: usize, peer: SocketAddr) {
+        let datagram = String::from_utf8_lossy(&self.buffer[..size]).into_owned();
+        let records = datagram.split('\n')
+            .map(|record| record.trim_end_matches('\r'))
+            .filter(|record| !record.is_empty())
+            .map(|record| Event::new(record.to_string()).received_on("udp", Some(peer.to_string())));
+
+        self.records.extend(records);
+    }
+}
+
+impl Stream for Datagrams {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, io::Error> {
+        loop {
+            if let Some(event) = self.records.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+
+            let (size, peer) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            };
+
+            let size = if size > self.max_size {
+                self.oversized += 1;
+                warn!("Datagram from {} is larger than {} bytes, truncated ({} so far)",
+                      peer,
+                      self.max_size,
+                      self.oversized);
+                self.max_size
+            } else {
+                size
+            };
+
+            self.split(size, peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net;
+    use tokio_core::reactor::Core;
+
+    /**
+     * Sends each datagram to a socket read with `max_size`, and collects `count` lines
+     */
+    fn receive(datagrams: &[&[u8]], max_size: usize, count: u64) -> Vec<String> {
+        let mut core = Core::new().unwrap();
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let address = socket.local_addr().unwrap();
+
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for datagram in datagrams {
+            client.send_to(datagram, address).unwrap();
+        }
+
+        let events = core.run(Datagrams::new(socket, max_size).take(count).collect()).unwrap();
+        assert!(events.iter().all(|event| event.peer == Some(client.local_addr().unwrap().to_string())));
+        events.into_iter().map(|event| event.line).collect()
+    }
+
+    #[test]
+    fn every_record_of_a_datagram_is_a_line() {
+        assert_eq!(receive(&[b"one\ntwo\r\n\nthree", b"four\n"], MAX_DATAGRAM_SIZE, 4),
+                   ["one", "two", "three", "four"]);
+    }
+
+    #[test]
+    fn a_datagram_without_newlines_is_a_line() {
+        assert_eq!(receive(&[b"a single record", b"another"], MAX_DATAGRAM_SIZE, 2),
+                   ["a single record", "another"]);
+    }
+
+    #[test]
+    fn larger_datagrams_are_truncated() {
+        assert_eq!(receive(&[b"0123456789", b"short"], 4, 2), ["0123", "shor"]);
+        assert_eq!(receive(&[b"01\n23456789", b"next"], 6, 3), ["01", "234", "next"]);
+    }
+}
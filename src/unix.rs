@@ -0,0 +1,133 @@
+use futures::future::{self, Future};
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::PathBuf;
+use tokio_core::reactor::Handle;
+use tokio_uds::{UnixListener, UnixStream};
+
+/**
+ * Destinations with this prefix are Unix sockets rather than `HOST:PORT`
+ */
+const PREFIX: &'static str = "unix:";
+
+/**
+ * `/path/to/socket` for a socket on the filesystem, `@name` for a socket in the abstract
+ * namespace
+ */
+pub fn socket_path(address: &str) -> PathBuf {
+    if address.starts_with('@') {
+        PathBuf::from(format!("\0{}", &address[1..]))
+    } else {
+        PathBuf::from(address)
+    }
+}
+
+/**
+ * The socket path of a `unix:/path/to/socket` or `unix:@name` destination
+ */
+pub fn destination(address: &str) -> Option<PathBuf> {
+    if address.starts_with(PREFIX) {
+        Some(socket_path(&address[PREFIX.len()..]))
+    } else {
+        None
+    }
+}
+
+fn is_abstract(path: &PathBuf) -> bool {
+    path.to_str().map(|path| path.starts_with('\0')).unwrap_or(false)
+}
+
+/**
+ * Binds a listener on `path`, first removing the socket left behind by a process that is not
+ * running anymore. `mode` is applied to filesystem sockets, abstract ones have no permissions.
+ */
+pub fn bind(path: &PathBuf, mode: Option<u32>, handle: &Handle) -> io::Result<UnixListener> {
+    if !is_abstract(path) {
+        remove_stale(path)?;
+    }
+
+    let listener = UnixListener::bind(path, handle)?;
+
+    if let (false, Some(mode)) = (is_abstract(path), mode) {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+
+    Ok(listener)
+}
+
+fn remove_stale(path: &PathBuf) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+
+    /**
+     * only a refused connection tells that nothing listens anymore, a socket which cannot be
+     * connected to for another reason may still be in use
+     */
+    match net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(ErrorKind::AddrInUse, format!("{} is in use by another process", path.display()))),
+        Err(ref err) if err.kind() == ErrorKind::ConnectionRefused => {
+            info!("Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(err) => {
+            Err(io::Error::new(err.kind(),
+                               format!("cannot tell whether {} is still in use: {}", path.display(), err)))
+        }
+    }
+}
+
+pub fn connect(path: &PathBuf, handle: &Handle) -> Box<Future<Item = UnixStream, Error = io::Error>> {
+    Box::new(future::result(UnixStream::connect(path, handle)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn socket(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("stubborn-sink-unix-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn a_socket_nothing_listens_on_is_removed() {
+        let path = socket("stale");
+        drop(net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        remove_stale(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_socket_in_use_is_kept() {
+        let path = socket("live");
+        let _listener = net::UnixListener::bind(&path).unwrap();
+
+        assert_eq!(remove_stale(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_kept() {
+        let path = socket("file");
+        fs::File::create(&path).unwrap();
+
+        assert_eq!(remove_stale(&path).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}